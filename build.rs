@@ -1,6 +1,9 @@
 // build.rs
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+/// First bytes of the ramdisk, see `kernel/src/ramdisk.rs` for the format
+const RAMDISK_MAGIC: &[u8; 8] = b"LABOSRD1";
 
 fn main() {
     // set by cargo, build scripts should use this directory for output files
@@ -9,15 +12,49 @@ fn main() {
     // https://doc.rust-lang.org/nightly/cargo/reference/unstable.html#artifact-dependencies
     let kernel = PathBuf::from(std::env::var_os("CARGO_BIN_FILE_KERNEL_kernel").unwrap());
 
+    // bundle the user programs in a ramdisk, the kernel finds them by name
+    let programs = [
+        (
+            "hello",
+            PathBuf::from(std::env::var_os("CARGO_BIN_FILE_HELLO_hello").unwrap()),
+        ),
+        (
+            "invaders",
+            PathBuf::from(std::env::var_os("CARGO_BIN_FILE_INVADERS_invaders").unwrap()),
+        ),
+    ];
+    let ramdisk_path = out_dir.join("ramdisk.img");
+    create_ramdisk(&programs, &ramdisk_path);
+
     // create an UEFI disk image (optional)
     let uefi_path = out_dir.join("uefi.img");
-    bootloader::UefiBoot::new(&kernel).create_disk_image(&uefi_path).unwrap();
+    bootloader::UefiBoot::new(&kernel)
+        .set_ramdisk(&ramdisk_path)
+        .create_disk_image(&uefi_path)
+        .unwrap();
 
     // create a BIOS disk image
     let bios_path = out_dir.join("bios.img");
-    bootloader::BiosBoot::new(&kernel).create_disk_image(&bios_path).unwrap();
+    bootloader::BiosBoot::new(&kernel)
+        .set_ramdisk(&ramdisk_path)
+        .create_disk_image(&bios_path)
+        .unwrap();
 
     // pass the disk image paths as env variables to the `main.rs`
     println!("cargo:rustc-env=UEFI_PATH={}", uefi_path.display());
     println!("cargo:rustc-env=BIOS_PATH={}", bios_path.display());
-}
\ No newline at end of file
+}
+
+/// Writes the programs to `path`, each as its name length (u32), its image length (u64), its
+/// name and its image, after `RAMDISK_MAGIC`. Numbers are little-endian.
+fn create_ramdisk(programs: &[(&str, PathBuf)], path: &Path) {
+    let mut ramdisk = RAMDISK_MAGIC.to_vec();
+    for (name, program) in programs {
+        let image = std::fs::read(program).unwrap();
+        ramdisk.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        ramdisk.extend_from_slice(&(image.len() as u64).to_le_bytes());
+        ramdisk.extend_from_slice(name.as_bytes());
+        ramdisk.extend_from_slice(&image);
+    }
+    std::fs::write(path, ramdisk).unwrap();
+}
@@ -1,28 +1,473 @@
 #[global_allocator]
-static ALLOCATOR: SequentialAllocator = SequentialAllocator;
+static ALLOCATOR: FreeListAllocator = FreeListAllocator::new();
 
+use crate::{crash_screen, end_crashed_run, memory, serial, serial_println, QemuExitCode};
 use alloc::alloc::{GlobalAlloc, Layout};
+use core::fmt::Write;
+use core::{fmt, ptr};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
 
-pub static mut HEAP_START: usize = 0x0;
-pub static mut HEAP_END: usize = 0x0;
+/// Start of the virtual heap range. The page directly below it is never mapped, so running off
+/// the start of the heap faults immediately.
+pub const HEAP_START: usize = 0x_4444_4444_0000;
+/// Size the heap may grow to. The page directly after this range is never mapped either.
+pub const HEAP_MAX_SIZE: usize = 32 * 1024 * 1024;
+/// Size mapped by `init_heap`
+const HEAP_INITIAL_SIZE: usize = 256 * 1024;
+/// Minimum size mapped each time the heap runs out of free blocks
+const HEAP_GROW_SIZE: usize = 64 * 1024;
+const PAGE_SIZE: usize = 4096;
 
-pub struct SequentialAllocator;
-unsafe impl GlobalAlloc for SequentialAllocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let allocated_ptr = HEAP_START;
-        HEAP_START += layout.size();
-        if HEAP_START > HEAP_END {
-            panic!("Out of memory");
+/// Every block handed out or kept in the free list is a multiple of this size and starts at an
+/// address aligned to it, so any leftover piece is always large enough to hold a `FreeBlock`.
+const BLOCK_SIZE: usize = 16;
+
+/// Header written at the start of every free block
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+/// Singly linked list of free blocks, sorted by address so that neighbours can be coalesced
+struct FreeList {
+    head: *mut FreeBlock,
+    /// End of the part of the heap range that is mapped or being mapped by `grow`
+    end: usize,
+    total: usize,
+    used: usize,
+    peak: usize,
+    allocated: usize,
+    freed: usize,
+    allocations: usize,
+}
+
+unsafe impl Send for FreeList {}
+
+impl FreeList {
+    const fn new() -> Self {
+        FreeList {
+            head: ptr::null_mut(),
+            end: HEAP_START,
+            total: 0,
+            used: 0,
+            peak: 0,
+            allocated: 0,
+            freed: 0,
+            allocations: 0,
+        }
+    }
+
+    /// Returns the block size and alignment actually used for the given layout.
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let size = align_up(layout.size().max(BLOCK_SIZE), BLOCK_SIZE);
+        let align = layout.align().max(BLOCK_SIZE);
+        (size, align)
+    }
+
+    /// Inserts the region into the list, merging it with the blocks directly before and after it.
+    ///
+    /// ## Safety
+    /// The region must be unused, writable memory that is not already part of the list.
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        debug_assert!(addr.is_multiple_of(BLOCK_SIZE) && size.is_multiple_of(BLOCK_SIZE));
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < addr {
+            prev = next;
+            next = (*next).next;
+        }
+
+        let block = addr as *mut FreeBlock;
+        block.write(FreeBlock { size, next });
+        if prev.is_null() {
+            self.head = block;
+        } else {
+            (*prev).next = block;
+        }
+
+        //- Merge with the following block
+        if !next.is_null() && addr + size == next as usize {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+        //- Merge with the preceding block
+        if !prev.is_null() && prev as usize + (*prev).size == addr {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        }
+    }
+
+    /// Takes the first block that fits the layout out of the list (first fit).
+    /// Returns a null pointer if no block is large enough.
+    unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::size_align(layout);
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut current = self.head;
+        while !current.is_null() {
+            let start = current as usize;
+            let end = start + (*current).size;
+            let next = (*current).next;
+            let alloc_start = align_up(start, align);
+            let alloc_end = alloc_start.saturating_add(size);
+            if alloc_end <= end {
+                if prev.is_null() {
+                    self.head = next;
+                } else {
+                    (*prev).next = next;
+                }
+                //- Give back the unused space in front of and behind the allocation
+                if alloc_start > start {
+                    self.add_free_region(start, alloc_start - start);
+                }
+                if end > alloc_end {
+                    self.add_free_region(alloc_end, end - alloc_end);
+                }
+                self.used += size;
+                self.peak = self.peak.max(self.used);
+                self.allocated += size;
+                self.allocations += 1;
+                return alloc_start as *mut u8;
+            }
+            prev = current;
+            current = next;
+        }
+        ptr::null_mut()
+    }
+
+    /// Returns a block obtained from `allocate` with the same layout to the list.
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::size_align(layout);
+        self.add_free_region(ptr as usize, size);
+        self.used -= size;
+        self.freed += size;
+        self.allocations -= 1;
+    }
+
+    /// Returns the size of the largest block in the list.
+    fn largest_free_block(&self) -> usize {
+        let mut largest = 0;
+        let mut current = self.head;
+        while !current.is_null() {
+            unsafe {
+                largest = largest.max((*current).size);
+                current = (*current).next;
+            }
+        }
+        largest
+    }
+}
+
+/// First-fit free-list allocator. Freed blocks are coalesced with their neighbours so the heap
+/// does not fragment over repeated game restarts.
+pub struct FreeListAllocator {
+    free_list: Mutex<FreeList>,
+}
+
+impl FreeListAllocator {
+    pub const fn new() -> Self {
+        FreeListAllocator {
+            free_list: Mutex::new(FreeList::new()),
+        }
+    }
+
+    /// Maps more pages at the end of the heap, at least `size` bytes if possible, and adds them to
+    /// the list. Returns false if `size` bytes no longer fit in what is left of the heap range,
+    /// without mapping anything, or if nothing could be mapped because physical memory ran out.
+    ///
+    /// The pages are mapped without holding the free-list lock, so the lock is never taken
+    /// before `memory`'s and the mapper is free to allocate.
+    fn grow(&self, size: usize) -> bool {
+        let needed = align_up(size, PAGE_SIZE);
+        //- Reserve the range first, so that a concurrent grow maps the one after it
+        let (start, size) = {
+            let mut free_list = self.free_list.lock();
+            let start = free_list.end;
+            let left = HEAP_START + HEAP_MAX_SIZE - start;
+            if needed > left {
+                // could never fit, so do not map the rest of the range for nothing
+                return false;
+            }
+            let size = needed.max(HEAP_GROW_SIZE).min(left);
+            free_list.end += size;
+            (start, size)
+        };
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let mut mapped = 0;
+        while mapped < size {
+            let page = VirtAddr::new((start + mapped) as u64);
+            if memory::map_pages(page, PAGE_SIZE as u64, flags).is_err() {
+                break;
+            }
+            mapped += PAGE_SIZE;
+        }
+
+        let mut free_list = self.free_list.lock();
+        if free_list.end == start + size {
+            // give back the part that could not be mapped, unless the range moved on meanwhile
+            free_list.end = start + mapped;
+        }
+        if mapped == 0 {
+            return false;
         }
-        allocated_ptr as *mut u8
+        unsafe { free_list.add_free_region(start, mapped) };
+        free_list.total += mapped;
+        true
+    }
+}
+
+impl Default for FreeListAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl GlobalAlloc for FreeListAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // The game allocates from interrupt handlers, so the lock must never be held while an
+        // interrupt can fire.
+        interrupts::without_interrupts(|| {
+            let ptr = self.free_list.lock().allocate(layout);
+            if ptr.is_null() && self.grow(layout.size() + layout.align()) {
+                return self.free_list.lock().allocate(layout);
+            }
+            ptr
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| self.free_list.lock().deallocate(ptr, layout))
+    }
+}
+
+/// Called when an infallible allocation fails. Shows a diagnostic on the screen, dumps the heap
+/// statistics over serial and ends the QEMU run. Fallible ones like `Vec::try_reserve` get their
+/// `Err` instead, since `alloc` itself only returns null.
+#[alloc_error_handler]
+fn out_of_memory(layout: Layout) -> ! {
+    interrupts::disable();
+    // the run ends here, whatever was printing when the allocation failed
+    unsafe { serial::force_unlock() };
+    serial_println!(
+        "OUT OF MEMORY: failed to allocate {} bytes (align {})",
+        layout.size(),
+        layout.align()
+    );
+    dump_stats();
+    let stats = stats();
+    crash_screen::show(
+        "OUT OF MEMORY",
+        format_args!(
+            "Requested:          {} B\nFree:               {} B\nLargest free block: {} B",
+            layout.size(),
+            stats.free,
+            stats.largest_free_block
+        ),
+    );
+    end_crashed_run(QemuExitCode::OutOfMemory);
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Maps the first part of the heap range. The rest is mapped on demand as the heap grows.
+/// `memory::init` must have been called before.
+pub fn init_heap() {
+    interrupts::without_interrupts(|| ALLOCATOR.grow(HEAP_INITIAL_SIZE));
+}
+
+/// Snapshot of the heap usage. All sizes are in bytes, counted in whole allocator blocks.
+pub struct HeapStats {
+    /// Bytes mapped into the heap range so far
+    pub total: usize,
+    pub used: usize,
+    pub free: usize,
+    /// Highest `used` value seen since boot
+    pub peak: usize,
+    /// Bytes handed out since boot
+    pub allocated: usize,
+    /// Bytes returned since boot
+    pub freed: usize,
+    /// Number of allocations that have not been freed yet
+    pub allocations: usize,
+    pub largest_free_block: usize,
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} KiB mapped, {} KiB used, {} KiB free",
+            self.total / 1024,
+            self.used / 1024,
+            self.free / 1024
+        )
     }
+}
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+/// Returns the current heap usage.
+pub fn stats() -> HeapStats {
+    interrupts::without_interrupts(|| {
+        let free_list = ALLOCATOR.free_list.lock();
+        HeapStats {
+            total: free_list.total,
+            used: free_list.used,
+            free: free_list.total - free_list.used,
+            peak: free_list.peak,
+            allocated: free_list.allocated,
+            freed: free_list.freed,
+            allocations: free_list.allocations,
+            largest_free_block: free_list.largest_free_block(),
+        }
+    })
 }
 
-pub fn init_heap(offset: usize, end: usize) {
-    unsafe {
-        HEAP_START = offset;
-        HEAP_END = end;
+/// Writes a detailed heap report to the serial port.
+pub fn dump_stats() {
+    let stats = stats();
+    serial::with_writer(|serial| {
+        writeln!(serial, "Heap stats:").unwrap();
+        writeln!(serial, "  mapped:             {} B", stats.total).unwrap();
+        writeln!(serial, "  used:               {} B", stats.used).unwrap();
+        writeln!(serial, "  free:               {} B", stats.free).unwrap();
+        writeln!(serial, "  peak:               {} B", stats.peak).unwrap();
+        writeln!(serial, "  allocated:          {} B", stats.allocated).unwrap();
+        writeln!(serial, "  freed:              {} B", stats.freed).unwrap();
+        writeln!(serial, "  live allocations:   {}", stats.allocations).unwrap();
+        writeln!(
+            serial,
+            "  largest free block: {} B",
+            stats.largest_free_block
+        )
+        .unwrap();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+
+    /// A free list over a region of `size` bytes of its own, taken from the heap. Returns the
+    /// list and the start of the region.
+    fn free_list(size: usize) -> (FreeList, usize) {
+        let layout = Layout::from_size_align(size, BLOCK_SIZE).unwrap();
+        let start = unsafe { alloc::alloc::alloc(layout) } as usize;
+        let mut free_list = FreeList::new();
+        unsafe { free_list.add_free_region(start, size) };
+        (free_list, start)
+    }
+
+    fn layout(size: usize, align: usize) -> Layout {
+        Layout::from_size_align(size, align).unwrap()
+    }
+
+    #[test_case]
+    fn first_fit_hands_out_whole_blocks() {
+        let (mut free_list, start) = free_list(1024);
+        unsafe {
+            assert_eq!(free_list.allocate(layout(1, 1)) as usize, start);
+            assert_eq!(
+                free_list.allocate(layout(20, 4)) as usize,
+                start + BLOCK_SIZE
+            );
+            assert_eq!(
+                free_list.allocate(layout(8, 8)) as usize,
+                start + 3 * BLOCK_SIZE
+            );
+        }
+        assert_eq!(free_list.used, 4 * BLOCK_SIZE);
+        assert_eq!(free_list.allocations, 3);
+    }
+
+    #[test_case]
+    fn large_alignments_are_honoured() {
+        let (mut free_list, _) = free_list(1024);
+        unsafe {
+            free_list.allocate(layout(16, 16));
+            let ptr = free_list.allocate(layout(32, 256));
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % 256, 0);
+        }
+    }
+
+    #[test_case]
+    fn freed_blocks_are_coalesced() {
+        let (mut free_list, start) = free_list(1024);
+        unsafe {
+            let blocks = [
+                free_list.allocate(layout(64, 16)),
+                free_list.allocate(layout(64, 16)),
+                free_list.allocate(layout(64, 16)),
+            ];
+            // the middle block last, so that it merges with free blocks on both sides
+            for index in [0, 2, 1] {
+                free_list.deallocate(blocks[index], layout(64, 16));
+            }
+        }
+        assert_eq!(free_list.head as usize, start);
+        assert!(unsafe { (*free_list.head).next }.is_null());
+        assert_eq!(free_list.largest_free_block(), 1024);
+        assert_eq!(free_list.used, 0);
+        assert_eq!(free_list.allocations, 0);
+    }
+
+    #[test_case]
+    fn exhausted_list_returns_null() {
+        let (mut free_list, _) = free_list(256);
+        unsafe {
+            assert!(free_list.allocate(layout(512, 16)).is_null());
+            assert!(!free_list.allocate(layout(256, 16)).is_null());
+            assert!(free_list.allocate(layout(1, 1)).is_null());
+        }
+    }
+
+    #[test_case]
+    fn heap_values_survive() {
+        let value = Box::new(41);
+        let values: Vec<u64> = (0..1000).collect();
+        assert_eq!(*value + 1, 42);
+        assert_eq!(values.iter().sum::<u64>(), 999 * 1000 / 2);
+    }
+
+    #[test_case]
+    fn freed_memory_is_reused() {
+        let total = stats().total;
+        for i in 0..HEAP_INITIAL_SIZE / 64 {
+            let value = Box::new([i; 8]);
+            assert_eq!(value[7], i);
+        }
+        assert_eq!(stats().total, total);
+    }
+
+    #[test_case]
+    fn failed_reserve_is_an_error() {
+        let mut values = Vec::<u8>::new();
+        assert!(values.try_reserve(HEAP_MAX_SIZE + 1).is_err());
+        assert!(values.try_reserve(16).is_ok());
+    }
+
+    #[test_case]
+    fn requests_past_the_heap_range_map_nothing() {
+        let total = stats().total;
+        let end = interrupts::without_interrupts(|| ALLOCATOR.free_list.lock().end);
+        let left = HEAP_START + HEAP_MAX_SIZE - end;
+        let mut values = Vec::<u8>::new();
+        assert!(values.try_reserve(left + PAGE_SIZE).is_err());
+        assert_eq!(stats().total, total);
+    }
+
+    #[test_case]
+    fn heap_grows_on_demand() {
+        let used = stats().used;
+        let large = Vec::<u8>::with_capacity(HEAP_INITIAL_SIZE * 2);
+        assert!(stats().total >= HEAP_INITIAL_SIZE * 3);
+        drop(large);
+        assert_eq!(stats().used, used);
     }
 }
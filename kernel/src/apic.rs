@@ -0,0 +1,283 @@
+use crate::interrupts::{InterruptIndex, PICS};
+use crate::{memory, time};
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+
+/// Vector the local APIC raises for spurious interrupts. Its low four bits must be set on older
+/// CPUs.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// Local APIC registers, as offsets from its base address
+const LAPIC_ID: u64 = 0x20;
+const LAPIC_TPR: u64 = 0x80;
+const LAPIC_EOI: u64 = 0xb0;
+const LAPIC_SVR: u64 = 0xf0;
+const LAPIC_LVT_TIMER: u64 = 0x320;
+const LAPIC_TIMER_INITIAL: u64 = 0x380;
+const LAPIC_TIMER_CURRENT: u64 = 0x390;
+const LAPIC_TIMER_DIVIDE: u64 = 0x3e0;
+
+const SVR_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_PERIODIC: u32 = 1 << 17;
+/// Divide configuration value that runs the timer at a sixteenth of the bus clock
+const TIMER_DIVIDE_16: u32 = 0b0011;
+/// How long the timer is measured against the PIT
+const CALIBRATION_MS: u32 = 10;
+
+// IO-APIC registers
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+
+/// ISA IRQs routed through the IO-APIC, and the vectors they raise
+const ISA_ROUTES: [(u8, InterruptIndex); 2] = [
+    (KEYBOARD_IRQ, InterruptIndex::Keyboard),
+    (SERIAL_IRQ, InterruptIndex::Serial),
+];
+/// ISA IRQ of the PS/2 keyboard
+const KEYBOARD_IRQ: u8 = 1;
+/// ISA IRQ of COM1
+const SERIAL_IRQ: u8 = 4;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Virtual address of the local APIC registers
+static LAPIC: AtomicU64 = AtomicU64::new(0);
+
+/// What the kernel needs from the ACPI MADT.
+struct Madt {
+    local_apic: u64,
+    /// Address and first global system interrupt of each IO-APIC
+    io_apics: [(u64, u32); 8],
+    io_apic_count: usize,
+    /// Global system interrupt each ISA IRQ is wired to, with the polarity and trigger mode
+    /// flags of its interrupt source override
+    isa_irqs: [(u32, u16); 16],
+}
+
+impl Madt {
+    /// Index of the IO-APIC handling `gsi`, the one with the closest base below it.
+    fn io_apic_for(&self, gsi: u32) -> Option<usize> {
+        (0..self.io_apic_count)
+            .filter(|&i| self.io_apics[i].1 <= gsi)
+            .max_by_key(|&i| self.io_apics[i].1)
+    }
+}
+
+/// Switches interrupt delivery from the 8259 PIC to the local APIC and the IO-APIC.
+/// The local APIC timer drives the timer interrupt, firing `hz` times per second or, without
+/// it, at the PIT's default rate. The keyboard and COM1 are routed through the IO-APIC.
+///
+/// Everything that can fail is done before the PIC is masked, so on error the PIC is still in
+/// charge and can be used instead.
+///
+/// ## Safety
+/// `memory::init` must have been called, `rsdp_addr` must be the physical address of the ACPI
+/// RSDP, and interrupts must be disabled.
+pub unsafe fn init(rsdp_addr: u64, hz: Option<u32>) -> Result<(), &'static str> {
+    let madt = parse_madt(rsdp_addr)?;
+
+    let lapic = memory::map_mmio(PhysAddr::new(madt.local_apic), 0x400)
+        .map_err(|_| "cannot map the local APIC")?;
+    // each IO-APIC is mapped once, for all the routes through it
+    let mut io_apics = [0; 8];
+    for (io_apic, (phys, _)) in io_apics
+        .iter_mut()
+        .zip(&madt.io_apics[..madt.io_apic_count])
+    {
+        *io_apic = memory::map_mmio(PhysAddr::new(*phys), 0x20)
+            .map_err(|_| "cannot map the IO-APIC")?
+            .as_u64();
+    }
+    // IO-APIC, redirection register and redirection entry of each ISA route
+    let mut routes = [(0, 0, 0); ISA_ROUTES.len()];
+    for (route, (irq, index)) in routes.iter_mut().zip(ISA_ROUTES) {
+        let (gsi, flags) = madt.isa_irqs[irq as usize];
+        let i = madt
+            .io_apic_for(gsi)
+            .ok_or("no IO-APIC for an ISA interrupt")?;
+        let (io_apic, gsi_base) = (io_apics[i], madt.io_apics[i].1);
+        let entries = ((io_apic_read(io_apic, IOAPICVER) >> 16) & 0xff) + 1;
+        if gsi - gsi_base >= entries {
+            return Err("the IO-APIC does not handle an ISA interrupt");
+        }
+        let mut redirection = index.as_u8() as u64;
+        if flags & 0b11 == 0b11 {
+            redirection |= REDIRECTION_ACTIVE_LOW;
+        }
+        if (flags >> 2) & 0b11 == 0b11 {
+            redirection |= REDIRECTION_LEVEL;
+        }
+        *route = (io_apic, IOREDTBL + 2 * (gsi - gsi_base), redirection);
+    }
+    LAPIC.store(lapic.as_u64(), Ordering::Relaxed);
+
+    let mut apic_base = Msr::new(IA32_APIC_BASE);
+    apic_base.write(apic_base.read() | APIC_BASE_ENABLE);
+    lapic_write(LAPIC_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+    lapic_write(LAPIC_TPR, 0);
+
+    // count down from the top for a known time to learn the timer's rate
+    lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    lapic_write(LAPIC_LVT_TIMER, LVT_MASKED);
+    lapic_write(LAPIC_TIMER_INITIAL, u32::MAX);
+    time::pit_delay(CALIBRATION_MS);
+    let elapsed = u32::MAX - lapic_read(LAPIC_TIMER_CURRENT);
+    let counts_per_ms = elapsed as u64 / CALIBRATION_MS as u64;
+    lapic_write(LAPIC_TIMER_INITIAL, 0);
+    if counts_per_ms == 0 {
+        return Err("the local APIC timer does not count");
+    }
+    let period_ns = match hz {
+        Some(hz) => {
+            assert!(hz > 0, "timer frequency must not be zero");
+            1_000_000_000 / hz as u64
+        }
+        None => time::tick_period(),
+    };
+    let count = (counts_per_ms * period_ns / 1_000_000).clamp(1, u32::MAX as u64);
+
+    PICS.lock().disable();
+    ENABLED.store(true, Ordering::SeqCst);
+
+    let destination = (lapic_read(LAPIC_ID) >> 24) as u64;
+    for (io_apic, register, redirection) in routes {
+        let redirection = redirection | destination << 56;
+        io_apic_write(io_apic, register + 1, (redirection >> 32) as u32);
+        io_apic_write(io_apic, register, redirection as u32);
+    }
+
+    lapic_write(
+        LAPIC_LVT_TIMER,
+        InterruptIndex::Timer.as_u8() as u32 | LVT_PERIODIC,
+    );
+    lapic_write(LAPIC_TIMER_INITIAL, count as u32);
+    time::set_tick_period(count * 1_000_000 / counts_per_ms);
+    Ok(())
+}
+
+/// Whether `init` has switched interrupt delivery to the APIC.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Signals the end of the interrupt being handled to the local APIC.
+pub fn end_of_interrupt() {
+    unsafe { lapic_write(LAPIC_EOI, 0) };
+}
+
+unsafe fn lapic_read(register: u64) -> u32 {
+    ptr::read_volatile((LAPIC.load(Ordering::Relaxed) + register) as *const u32)
+}
+
+unsafe fn lapic_write(register: u64, value: u32) {
+    ptr::write_volatile(
+        (LAPIC.load(Ordering::Relaxed) + register) as *mut u32,
+        value,
+    );
+}
+
+unsafe fn io_apic_read(io_apic: u64, register: u32) -> u32 {
+    ptr::write_volatile((io_apic + IOREGSEL) as *mut u32, register);
+    ptr::read_volatile((io_apic + IOWIN) as *const u32)
+}
+
+unsafe fn io_apic_write(io_apic: u64, register: u32, value: u32) {
+    ptr::write_volatile((io_apic + IOREGSEL) as *mut u32, register);
+    ptr::write_volatile((io_apic + IOWIN) as *mut u32, value);
+}
+
+//- ACPI
+
+/// Reads `T` from physical memory, through the bootloader's mapping of all of it.
+unsafe fn read_phys<T: Copy>(addr: u64) -> T {
+    ptr::read_unaligned(memory::phys_to_virt(PhysAddr::new(addr)).as_ptr())
+}
+
+/// Whether the bytes of `addr..addr + len` add up to zero, as every ACPI structure's do.
+unsafe fn checksum_ok(addr: u64, len: u64) -> bool {
+    (0..len).fold(0u8, |sum, i| sum.wrapping_add(read_phys(addr + i))) == 0
+}
+
+/// Walks from the RSDP to the MADT and collects the APIC addresses and the ISA IRQs' routing.
+unsafe fn parse_madt(rsdp_addr: u64) -> Result<Madt, &'static str> {
+    if read_phys::<[u8; 8]>(rsdp_addr) != *b"RSD PTR " || !checksum_ok(rsdp_addr, 20) {
+        return Err("invalid ACPI RSDP");
+    }
+    let revision: u8 = read_phys(rsdp_addr + 15);
+    // ACPI 2.0 and later have an XSDT with 64-bit table pointers
+    let (root, entry_size) = if revision >= 2 {
+        (read_phys::<u64>(rsdp_addr + 24), 8)
+    } else {
+        (read_phys::<u32>(rsdp_addr + 16) as u64, 4)
+    };
+    let root_len: u32 = read_phys(root + 4);
+    if !checksum_ok(root, root_len as u64) {
+        return Err("invalid ACPI root table");
+    }
+
+    let madt = (36..root_len as u64)
+        .step_by(entry_size)
+        .map(|offset| match entry_size {
+            8 => read_phys::<u64>(root + offset),
+            _ => read_phys::<u32>(root + offset) as u64,
+        })
+        .find(|&table| read_phys::<[u8; 4]>(table) == *b"APIC")
+        .ok_or("no MADT in the ACPI tables")?;
+    let madt_len: u32 = read_phys(madt + 4);
+    if !checksum_ok(madt, madt_len as u64) {
+        return Err("invalid MADT");
+    }
+
+    let mut local_apic = read_phys::<u32>(madt + 36) as u64;
+    let mut io_apics = [(0u64, 0u32); 8];
+    let mut io_apic_count = 0;
+    // identity mapped unless overridden
+    let mut isa_irqs: [(u32, u16); 16] = core::array::from_fn(|irq| (irq as u32, 0));
+    let mut offset = 44;
+    while offset + 2 <= madt_len as u64 {
+        let entry = madt + offset;
+        let kind: u8 = read_phys(entry);
+        let len: u8 = read_phys(entry + 1);
+        if len < 2 {
+            return Err("malformed MADT entry");
+        }
+        match kind {
+            // IO-APIC
+            1 if io_apic_count < io_apics.len() => {
+                io_apics[io_apic_count] = (
+                    read_phys::<u32>(entry + 4) as u64,
+                    read_phys::<u32>(entry + 8),
+                );
+                io_apic_count += 1;
+            }
+            // interrupt source override
+            2 => {
+                let irq: u8 = read_phys(entry + 3);
+                if let Some(routing) = isa_irqs.get_mut(irq as usize) {
+                    *routing = (read_phys(entry + 4), read_phys(entry + 8));
+                }
+            }
+            // local APIC address override
+            5 => local_apic = read_phys(entry + 4),
+            _ => {}
+        }
+        offset += len as u64;
+    }
+
+    Ok(Madt {
+        local_apic,
+        io_apics,
+        io_apic_count,
+        isa_irqs,
+    })
+}
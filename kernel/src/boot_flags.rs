@@ -0,0 +1,81 @@
+use lazy_static::lazy_static;
+use x86_64::instructions::port::{Port, PortWriteOnly};
+
+// Flags the host passes when it starts the machine, through QEMU's firmware configuration
+// device: `-fw_cfg "name=opt/lab-os/flags,string=telemetry exit-on-panic"`.
+
+/// I/O port selecting the fw_cfg item to read
+const SELECTOR_PORT: u16 = 0x510;
+/// I/O port the selected item is read from, one byte at a time
+const DATA_PORT: u16 = 0x511;
+/// Item holding "QEMU" when the device is there
+const SIGNATURE_KEY: u16 = 0x0000;
+/// Item listing the named items, like the flags
+const FILE_DIR_KEY: u16 = 0x0019;
+const FLAGS_NAME: &[u8] = b"opt/lab-os/flags";
+const MAX_FLAGS_LEN: usize = 256;
+
+lazy_static! {
+    /// The flags, separated by spaces, read on first use. Empty without the device or the flags
+    /// item.
+    static ref FLAGS: ([u8; MAX_FLAGS_LEN], usize) = unsafe { read_flags() };
+}
+
+/// Whether the host passed the given flag.
+pub fn is_set(flag: &str) -> bool {
+    let (flags, len) = &*FLAGS;
+    flags[..*len]
+        .split(u8::is_ascii_whitespace)
+        .any(|name| name == flag.as_bytes())
+}
+
+/// Reads the flags item.
+///
+/// ## Safety
+/// The fw_cfg ports must not be in use elsewhere.
+unsafe fn read_flags() -> ([u8; MAX_FLAGS_LEN], usize) {
+    let mut flags = [0; MAX_FLAGS_LEN];
+    let mut selector = PortWriteOnly::<u16>::new(SELECTOR_PORT);
+    let mut data = Port::<u8>::new(DATA_PORT);
+    let mut read = |bytes: &mut [u8]| {
+        for byte in bytes {
+            *byte = data.read();
+        }
+    };
+
+    selector.write(SIGNATURE_KEY);
+    let mut signature = [0; 4];
+    read(&mut signature);
+    if &signature != b"QEMU" {
+        return (flags, 0);
+    }
+
+    //- Find the item in the directory, whose fields are big-endian
+    selector.write(FILE_DIR_KEY);
+    let mut count = [0; 4];
+    read(&mut count);
+    let mut item = None;
+    for _ in 0..u32::from_be_bytes(count) {
+        let (mut size, mut key, mut reserved, mut name) = ([0; 4], [0; 2], [0; 2], [0; 56]);
+        read(&mut size);
+        read(&mut key);
+        read(&mut reserved);
+        read(&mut name);
+        if name.split(|&byte| byte == 0).next() == Some(FLAGS_NAME) {
+            item = Some((u16::from_be_bytes(key), u32::from_be_bytes(size) as usize));
+            break;
+        }
+    }
+    let Some((key, size)) = item else {
+        return (flags, 0);
+    };
+
+    selector.write(key);
+    let len = size.min(MAX_FLAGS_LEN);
+    read(&mut flags[..len]);
+    let len = flags[..len]
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(len);
+    (flags, len)
+}
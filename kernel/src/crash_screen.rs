@@ -0,0 +1,175 @@
+use crate::RacyCell;
+use bootloader_api::info::{FrameBuffer, FrameBufferInfo, PixelFormat};
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::ptr;
+use noto_sans_mono_bitmap::{get_raster, FontWeight, RasterHeight, RasterizedChar};
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::registers::rflags;
+
+static SCREEN: RacyCell<Option<CrashScreen>> = RacyCell::new(None);
+
+const BACKGROUND: [u8; 3] = [0x00, 0x00, 0xaa];
+const FOREGROUND: [u8; 3] = [0xff, 0xff, 0xff];
+const MARGIN: usize = 20;
+
+/// Framebuffer writer used when the kernel crashes. It writes through its own pointer to the
+/// framebuffer, so it works no matter what state the game's screen writer was left in.
+pub struct CrashScreen {
+    buffer: *mut u8,
+    info: FrameBufferInfo,
+    x_pos: usize,
+    y_pos: usize,
+}
+
+unsafe impl Send for CrashScreen {}
+unsafe impl Sync for CrashScreen {}
+
+/// Registers the framebuffer the crash screen is drawn on.
+pub fn init(framebuffer: &mut FrameBuffer) {
+    let info = framebuffer.info();
+    let buffer = framebuffer.buffer_mut().as_mut_ptr();
+    *unsafe { SCREEN.get_mut() } = Some(CrashScreen {
+        buffer,
+        info,
+        x_pos: MARGIN,
+        y_pos: MARGIN,
+    });
+}
+
+/// Paints the whole screen and shows the title followed by the details.
+/// Does nothing if `init` has not been called.
+pub fn show(title: &str, details: fmt::Arguments) {
+    if let Some(screen) = unsafe { SCREEN.get_mut() } {
+        screen.clear();
+        let _ = write!(screen, "{title}\n\n{details}");
+    }
+}
+
+impl CrashScreen {
+    fn clear(&mut self) {
+        for y in 0..self.info.height {
+            for x in 0..self.info.width {
+                self.write_pixel(x, y, BACKGROUND);
+            }
+        }
+        self.x_pos = MARGIN;
+        self.y_pos = MARGIN;
+    }
+
+    fn newline(&mut self) {
+        self.x_pos = MARGIN;
+        self.y_pos += RasterHeight::Size16 as usize;
+    }
+
+    fn write_char(&mut self, c: char) {
+        match c {
+            '\n' => self.newline(),
+            c => {
+                let rendered = get_raster(c, FontWeight::Regular, RasterHeight::Size16)
+                    .or_else(|| get_raster('?', FontWeight::Regular, RasterHeight::Size16));
+                if let Some(rendered) = rendered {
+                    if self.x_pos + rendered.width() > self.info.width - MARGIN {
+                        self.newline();
+                    }
+                    self.write_rendered_char(rendered);
+                }
+            }
+        }
+    }
+
+    fn write_rendered_char(&mut self, rendered_char: RasterizedChar) {
+        for (y, row) in rendered_char.raster().iter().enumerate() {
+            for (x, intensity) in row.iter().enumerate() {
+                let color = blend(*intensity);
+                self.write_pixel(self.x_pos + x, self.y_pos + y, color);
+            }
+        }
+        self.x_pos += rendered_char.width();
+    }
+
+    fn write_pixel(&mut self, x: usize, y: usize, [r, g, b]: [u8; 3]) {
+        if x >= self.info.width || y >= self.info.height {
+            return;
+        }
+        let color = match self.info.pixel_format {
+            PixelFormat::Rgb => [r, g, b, 0],
+            PixelFormat::Bgr => [b, g, r, 0],
+            PixelFormat::U8 => [r / 3 + g / 3 + b / 3, 0, 0, 0],
+            // nothing sensible to draw, and panicking here would hide the original crash
+            _ => return,
+        };
+        let bytes_per_pixel = self.info.bytes_per_pixel.min(color.len());
+        let byte_offset = (y * self.info.stride + x) * self.info.bytes_per_pixel;
+        for (i, byte) in color[..bytes_per_pixel].iter().enumerate() {
+            unsafe { ptr::write_volatile(self.buffer.add(byte_offset + i), *byte) };
+        }
+    }
+}
+
+impl fmt::Write for CrashScreen {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            self.write_char(c);
+        }
+        Ok(())
+    }
+}
+
+/// Mixes the foreground into the background color by the given glyph intensity.
+fn blend(intensity: u8) -> [u8; 3] {
+    let mut color = [0; 3];
+    for (i, channel) in color.iter_mut().enumerate() {
+        let background = BACKGROUND[i] as u16;
+        let foreground = FOREGROUND[i] as u16;
+        let intensity = intensity as u16;
+        *channel = ((background * (255 - intensity) + foreground * intensity) / 255) as u8;
+    }
+    color
+}
+
+/// Stack and control registers, captured where a crash is reported.
+pub struct RegisterDump {
+    rsp: u64,
+    rbp: u64,
+    rflags: u64,
+    cr0: u64,
+    cr2: u64,
+    cr3: u64,
+    cr4: u64,
+}
+
+impl RegisterDump {
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let rsp: u64;
+        let rbp: u64;
+        unsafe {
+            asm!(
+                "mov {}, rsp",
+                "mov {}, rbp",
+                out(reg) rsp,
+                out(reg) rbp,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+        RegisterDump {
+            rsp,
+            rbp,
+            rflags: rflags::read_raw(),
+            cr0: Cr0::read_raw(),
+            cr2: Cr2::read().as_u64(),
+            cr3: Cr3::read().0.start_address().as_u64(),
+            cr4: Cr4::read_raw(),
+        }
+    }
+}
+
+impl fmt::Display for RegisterDump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "RSP: {:#018x}  RBP: {:#018x}", self.rsp, self.rbp)?;
+        writeln!(f, "RFLAGS: {:#x}", self.rflags)?;
+        writeln!(f, "CR0: {:#018x}  CR2: {:#018x}", self.cr0, self.cr2)?;
+        write!(f, "CR3: {:#018x}  CR4: {:#018x}", self.cr3, self.cr4)
+    }
+}
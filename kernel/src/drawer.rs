@@ -0,0 +1,124 @@
+mod screen;
+
+use bootloader_api::info::FrameBuffer;
+use core::fmt;
+use kernel::logger::Sink;
+use screen::with_screenwriter;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Lines the log console shows
+const CONSOLE_LINES: usize = 5;
+/// Characters per log console line, longer lines are cut
+const CONSOLE_COLUMNS: usize = 40;
+/// Height of a log console line, in pixels
+const CONSOLE_LINE_HEIGHT: usize = 16;
+
+/// Lines the `LogConsole` sink keeps until `draw_log_console` puts them on the screen
+static CONSOLE: Mutex<ConsoleLines> = Mutex::new(ConsoleLines {
+    top_left: (0, 0),
+    lines: [ConsoleLine::EMPTY; CONSOLE_LINES],
+    dirty: false,
+});
+
+pub fn init(framebuffer: &'static mut FrameBuffer) {
+    screen::init(framebuffer);
+}
+
+/// Screen user programs draw on through the drawing syscalls
+pub struct SyscallScreen;
+
+impl kernel::syscall::Screen for SyscallScreen {
+    fn draw_rect(&mut self, x: usize, y: usize, width: usize, height: usize, rgb: [u8; 3]) {
+        // clipped, the arguments come from user programs
+        with_screenwriter(|writer| writer.fill_rect(x, y, width, height, rgb));
+    }
+
+    fn write_text(&mut self, x: usize, y: usize, text: &str) -> Result<(), &'static str> {
+        with_screenwriter(|writer| writer.write_text(x, y, text))
+    }
+}
+
+struct ConsoleLines {
+    top_left: (usize, usize),
+    /// Oldest at the top
+    lines: [ConsoleLine; CONSOLE_LINES],
+    /// Whether the lines changed since they were last drawn
+    dirty: bool,
+}
+
+/// A log console line, cut at `CONSOLE_COLUMNS` characters
+#[derive(Clone, Copy)]
+struct ConsoleLine {
+    bytes: [u8; CONSOLE_COLUMNS * 4],
+    len: usize,
+    chars: usize,
+}
+
+impl ConsoleLine {
+    const EMPTY: ConsoleLine = ConsoleLine {
+        bytes: [0; CONSOLE_COLUMNS * 4],
+        len: 0,
+        chars: 0,
+    };
+
+    fn as_str(&self) -> &str {
+        // only whole characters are ever copied in
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+}
+
+impl fmt::Write for ConsoleLine {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        for c in text.chars().take(CONSOLE_COLUMNS - self.chars) {
+            let end = self.len + c.len_utf8();
+            c.encode_utf8(&mut self.bytes[self.len..end]);
+            self.len = end;
+            self.chars += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Log sink keeping the last lines logged for `draw_log_console`. It does not draw itself,
+/// since sinks run with interrupts disabled.
+pub struct LogConsole;
+
+impl LogConsole {
+    pub fn new(top_left: (usize, usize)) -> LogConsole {
+        without_interrupts(|| CONSOLE.lock().top_left = top_left);
+        LogConsole
+    }
+}
+
+impl Sink for LogConsole {
+    fn write(&mut self, _level: log::Level, line: fmt::Arguments) {
+        let mut text = ConsoleLine::EMPTY;
+        let _ = fmt::write(&mut text, line);
+        let mut console = CONSOLE.lock();
+        console.lines.rotate_left(1);
+        console.lines[CONSOLE_LINES - 1] = text;
+        console.dirty = true;
+    }
+}
+
+/// Draws the lines logged to the `LogConsole` if they changed since the last call. Called by a
+/// timer, outside the logger.
+pub fn draw_log_console() {
+    let changed = without_interrupts(|| {
+        let mut console = CONSOLE.lock();
+        let dirty = core::mem::replace(&mut console.dirty, false);
+        dirty.then_some((console.top_left, console.lines))
+    });
+    let Some(((x, y), lines)) = changed else {
+        return;
+    };
+    with_screenwriter(|writer| {
+        let (width, height) = (8 * CONSOLE_COLUMNS, CONSOLE_LINES * CONSOLE_LINE_HEIGHT);
+        writer.fill_rect(x, y, width, height, [0, 0, 0]);
+        for (i, line) in lines.iter().enumerate() {
+            writer.set_cursor(x, y + i * CONSOLE_LINE_HEIGHT);
+            writer.write_str(line.as_str());
+        }
+    });
+}
@@ -0,0 +1,316 @@
+// Original code from rust-osdev/bootloader crate https://github.com/rust-osdev/bootloader
+
+use bootloader_api::info::{FrameBuffer, FrameBufferInfo, PixelFormat};
+use core::{fmt, ptr};
+use noto_sans_mono_bitmap::RasterHeight::Size16;
+use noto_sans_mono_bitmap::{get_raster, FontWeight, RasterizedChar};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+static WRITER: Mutex<Option<ScreenWriter>> = Mutex::new(None);
+
+/// Runs `f` on the screen writer, with interrupts disabled so that nothing else can draw in
+/// the middle or move the cursor. Panics if `init` has not been called.
+pub fn with_screenwriter<R>(f: impl FnOnce(&mut ScreenWriter) -> R) -> R {
+    without_interrupts(|| f(WRITER.lock().as_mut().expect("screen not initialised")))
+}
+
+pub fn init(buffer: &'static mut FrameBuffer) {
+    let info = buffer.info();
+    let framebuffer = buffer.buffer_mut();
+    let writer = ScreenWriter::new(framebuffer, info);
+    without_interrupts(|| *WRITER.lock() = Some(writer));
+}
+
+/// Additional vertical space between lines
+const LINE_SPACING: usize = 0;
+
+pub struct ScreenWriter {
+    framebuffer: &'static mut [u8],
+    info: FrameBufferInfo,
+    x_pos: usize,
+    y_pos: usize,
+}
+
+impl ScreenWriter {
+    pub fn new(framebuffer: &'static mut [u8], info: FrameBufferInfo) -> Self {
+        let mut logger = Self {
+            framebuffer,
+            info,
+            x_pos: 0,
+            y_pos: 0,
+        };
+        logger.clear();
+        logger
+    }
+
+    fn newline(&mut self) {
+        self.y_pos = self.y_pos.saturating_add(Size16 as usize + LINE_SPACING);
+        self.carriage_return()
+    }
+
+    fn carriage_return(&mut self) {
+        self.x_pos = 0;
+    }
+
+    pub fn set_cursor(&mut self, x: usize, y: usize) {
+        self.x_pos = x;
+        self.y_pos = y;
+    }
+
+    /// Erases all text on the screen.
+    pub fn clear(&mut self) {
+        self.x_pos = 0;
+        self.y_pos = 0;
+        self.framebuffer.fill(0);
+    }
+
+    pub fn width(&self) -> usize {
+        self.info.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.info.height
+    }
+
+    pub fn write_str(&mut self, text: &str) {
+        for c in text.chars() {
+            self.write_char(c);
+        }
+    }
+
+    pub fn write_char(&mut self, c: char) {
+        match c {
+            '\n' => self.newline(),
+            '\r' => self.carriage_return(),
+            c => {
+                if let Some(bitmap_char) = get_raster(c, FontWeight::Regular, Size16) {
+                    if !self.fits(self.x_pos, bitmap_char.width(), self.width()) {
+                        self.newline();
+                    }
+                    if !self.fits(self.y_pos, bitmap_char.height(), self.height()) {
+                        self.clear();
+                    }
+                    self.write_rendered_char(bitmap_char);
+                }
+            }
+        }
+    }
+
+    /// Writes `text` from `x`, `y` on, for user programs. Unlike `write_str`, text running past
+    /// the bottom is cut instead of clearing the screen. Fails if the first line does not fit.
+    pub fn write_text(&mut self, x: usize, y: usize, text: &str) -> Result<(), &'static str> {
+        if x >= self.width() || !self.fits(y, Size16 as usize, self.height()) {
+            return Err("text starts off the screen");
+        }
+        self.set_cursor(x, y);
+        for c in text.chars() {
+            match c {
+                '\n' => self.newline(),
+                '\r' => self.carriage_return(),
+                c => {
+                    let Some(bitmap_char) = get_raster(c, FontWeight::Regular, Size16) else {
+                        continue;
+                    };
+                    if !self.fits(self.x_pos, bitmap_char.width(), self.width()) {
+                        self.newline();
+                    }
+                    if !self.fits(self.y_pos, bitmap_char.height(), self.height()) {
+                        break;
+                    }
+                    self.write_rendered_char(bitmap_char);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether something `size` pixels long starting at `start` ends within `limit`.
+    fn fits(&self, start: usize, size: usize, limit: usize) -> bool {
+        start.checked_add(size).is_some_and(|end| end <= limit)
+    }
+
+    fn write_rendered_char(&mut self, rendered_char: RasterizedChar) {
+        for (y, row) in rendered_char.raster().iter().enumerate() {
+            for (x, byte) in row.iter().enumerate() {
+                self.write_pixel(self.x_pos + x, self.y_pos + y, *byte);
+            }
+        }
+        self.x_pos += rendered_char.width();
+    }
+
+    pub fn write_pixel(&mut self, x: usize, y: usize, intensity: u8) {
+        let pixel_offset = y * self.info.stride + x;
+        let color = match self.info.pixel_format {
+            PixelFormat::Rgb => [intensity / 4, intensity, intensity / 2, 0],
+            PixelFormat::Bgr => [intensity / 2, intensity, intensity / 4, 0],
+            other => {
+                // set a supported (but invalid) pixel format before panicking to avoid a double
+                // panic; it might not be readable though
+                self.info.pixel_format = PixelFormat::Rgb;
+                panic!("pixel format {:?} not supported in logger", other)
+            }
+        };
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        let byte_offset = pixel_offset * bytes_per_pixel;
+        self.framebuffer[byte_offset..(byte_offset + bytes_per_pixel)]
+            .copy_from_slice(&color[..bytes_per_pixel]);
+        let _ = unsafe { ptr::read_volatile(&self.framebuffer[byte_offset]) };
+    }
+
+    /// Fills the part of the rectangle with its top left corner at `x`, `y` that is on the
+    /// screen, one row at a time.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, rgb: [u8; 3]) {
+        let right = x.saturating_add(width).min(self.width());
+        let bottom = y.saturating_add(height).min(self.height());
+        if x >= right || y >= bottom {
+            return;
+        }
+        let color = self.color(rgb);
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        for y in y..bottom {
+            let start = (y * self.info.stride + x) * bytes_per_pixel;
+            let end = (y * self.info.stride + right) * bytes_per_pixel;
+            for pixel in self.framebuffer[start..end].chunks_exact_mut(bytes_per_pixel) {
+                pixel.copy_from_slice(&color[..bytes_per_pixel]);
+            }
+        }
+    }
+
+    /// The bytes of a pixel of colour `rgb` in the framebuffer's format.
+    fn color(&mut self, [r, g, b]: [u8; 3]) -> [u8; 4] {
+        match self.info.pixel_format {
+            PixelFormat::Rgb => [r, g, b, 0],
+            PixelFormat::Bgr => [b, g, r, 0],
+            other => {
+                // set a supported (but invalid) pixel format before panicking to avoid a double
+                // panic; it might not be readable though
+                self.info.pixel_format = PixelFormat::Rgb;
+                panic!("pixel format {:?} not supported in logger", other)
+            }
+        }
+    }
+}
+
+unsafe impl Send for ScreenWriter {}
+unsafe impl Sync for ScreenWriter {}
+
+impl fmt::Write for ScreenWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            self.write_char(c);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    const WIDTH: usize = 64;
+    const HEIGHT: usize = 32;
+
+    /// A writer on a framebuffer of its own, on the heap.
+    fn writer(pixel_format: PixelFormat) -> ScreenWriter {
+        let info = FrameBufferInfo {
+            byte_len: WIDTH * HEIGHT * 4,
+            width: WIDTH,
+            height: HEIGHT,
+            pixel_format,
+            bytes_per_pixel: 4,
+            stride: WIDTH,
+        };
+        ScreenWriter::new(vec![0; info.byte_len].leak(), info)
+    }
+
+    #[test_case]
+    fn text_continues_at_the_cursor() {
+        let mut writer = writer(PixelFormat::Rgb);
+        writer.set_cursor(10, 4);
+        writer.write_str("042");
+        let digit_width = get_raster('0', FontWeight::Regular, Size16)
+            .unwrap()
+            .width();
+        assert_eq!(writer.x_pos, 10 + 3 * digit_width);
+        assert_eq!(writer.y_pos, 4);
+        assert!(writer.framebuffer.iter().any(|byte| *byte != 0));
+    }
+
+    /// The bytes of the pixel at `x`, `y`.
+    fn pixel(writer: &ScreenWriter, x: usize, y: usize) -> &[u8] {
+        let offset = (y * WIDTH + x) * 4;
+        &writer.framebuffer[offset..offset + 4]
+    }
+
+    fn lit_pixels(writer: &ScreenWriter) -> usize {
+        writer
+            .framebuffer
+            .chunks(4)
+            .filter(|pixel| pixel.iter().any(|byte| *byte != 0))
+            .count()
+    }
+
+    #[test_case]
+    fn rectangles_are_filled_in_the_pixel_format() {
+        let mut writer = writer(PixelFormat::Bgr);
+        writer.fill_rect(1, 2, 3, 2, [1, 2, 3]);
+        assert_eq!(pixel(&writer, 1, 2), [3, 2, 1, 0]);
+        assert_eq!(pixel(&writer, 3, 3), [3, 2, 1, 0]);
+        assert_eq!(lit_pixels(&writer), 6);
+    }
+
+    #[test_case]
+    fn rectangles_are_clipped_to_the_screen() {
+        let mut writer = writer(PixelFormat::Rgb);
+        writer.fill_rect(
+            WIDTH - 2,
+            HEIGHT - 2,
+            usize::MAX,
+            usize::MAX,
+            [255, 255, 255],
+        );
+        assert_eq!(pixel(&writer, WIDTH - 1, HEIGHT - 1), [255, 255, 255, 0]);
+        assert_eq!(lit_pixels(&writer), 4);
+
+        writer.clear();
+        writer.fill_rect(WIDTH, 0, 10, 10, [255, 255, 255]);
+        writer.fill_rect(0, usize::MAX, 10, 10, [255, 255, 255]);
+        assert_eq!(lit_pixels(&writer), 0);
+    }
+
+    #[test_case]
+    fn text_starting_off_the_screen_is_rejected() {
+        let mut writer = writer(PixelFormat::Rgb);
+        assert!(writer.write_text(WIDTH, 0, "1").is_err());
+        assert!(writer.write_text(0, HEIGHT - 8, "1").is_err());
+        assert!(writer.write_text(usize::MAX, usize::MAX, "1").is_err());
+        assert_eq!(lit_pixels(&writer), 0);
+    }
+
+    #[test_case]
+    fn text_past_the_bottom_is_cut() {
+        let mut writer = writer(PixelFormat::Rgb);
+        writer.fill_rect(0, 0, WIDTH, 1, [255, 255, 255]);
+        // wraps onto a second line that does not fit
+        assert!(writer.write_text(0, HEIGHT - 16, "0123456789").is_ok());
+        assert_eq!(pixel(&writer, 0, 0), [255, 255, 255, 0]);
+        assert!(writer.y_pos + 16 > HEIGHT);
+    }
+
+    #[test_case]
+    fn text_at_a_huge_cursor_does_not_overflow() {
+        let mut writer = writer(PixelFormat::Rgb);
+        writer.set_cursor(usize::MAX, usize::MAX);
+        writer.write_str("1");
+        assert_eq!(writer.y_pos, 0);
+        assert!(lit_pixels(&writer) > 0);
+    }
+
+    kernel::should_panic! {
+        fn unsupported_pixel_format_panics() {
+            writer(PixelFormat::U8).write_str("1");
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use crate::memory::AddressSpace;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use x86_64::VirtAddr;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const CLASS_64: u8 = 2;
+const LITTLE_ENDIAN: u8 = 1;
+/// Type of position-independent executables
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+
+const DT_NULL: i64 = 0;
+const DT_RELA: i64 = 7;
+const DT_RELASZ: i64 = 8;
+const DT_RELAENT: i64 = 9;
+
+const R_X86_64_RELATIVE: u64 = 8;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FileHeader {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    program_header_offset: u64,
+    section_header_offset: u64,
+    flags: u32,
+    header_size: u16,
+    program_header_size: u16,
+    program_header_count: u16,
+    section_header_size: u16,
+    section_header_count: u16,
+    section_names_index: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    file_size: u64,
+    memory_size: u64,
+    align: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Dynamic {
+    tag: i64,
+    value: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Rela {
+    offset: u64,
+    info: u64,
+    addend: i64,
+}
+
+/// Reads a `T` at `offset` in `bytes`, which need not be aligned.
+fn read<T: Copy>(bytes: &[u8], offset: u64) -> Result<T, &'static str> {
+    let range = range(bytes, offset, size_of::<T>() as u64)?;
+    Ok(unsafe { core::ptr::read_unaligned(bytes[range].as_ptr() as *const T) })
+}
+
+/// Reads the relocation at `addr` in `space`, where the program has been loaded.
+fn read_relocation(space: &AddressSpace, addr: VirtAddr) -> Result<Rela, &'static str> {
+    let mut bytes = [0; size_of::<Rela>()];
+    space.read(addr, &mut bytes)?;
+    read(&bytes, 0)
+}
+
+/// Returns `offset..offset + len` if it lies within `bytes`.
+fn range(bytes: &[u8], offset: u64, len: u64) -> Result<core::ops::Range<usize>, &'static str> {
+    match offset.checked_add(len) {
+        Some(end) if end <= bytes.len() as u64 => Ok(offset as usize..end as usize),
+        _ => Err("ELF file is truncated or has an offset out of bounds"),
+    }
+}
+
+/// Loads a position-independent ELF executable at `base` in `space`, where `size` bytes must be
+/// free, and applies its relocations. Returns the address of its entry point. What was mapped
+/// stays mapped on error, and goes with `space`.
+///
+/// Only `R_X86_64_RELATIVE` relocations are supported, which is all a static-pie executable,
+/// like those rustc builds for `x86_64-unknown-none`, contains. Segments are mapped writable
+/// and executable whatever their flags.
+pub(crate) fn load(
+    image: &[u8],
+    space: &mut AddressSpace,
+    base: VirtAddr,
+    size: u64,
+) -> Result<VirtAddr, &'static str> {
+    let header: FileHeader = read(image, 0)?;
+    if &header.ident[..4] != ELF_MAGIC {
+        return Err("not an ELF file");
+    }
+    if header.ident[4] != CLASS_64
+        || header.ident[5] != LITTLE_ENDIAN
+        || header.machine != EM_X86_64
+    {
+        return Err("not an x86_64 ELF file");
+    }
+    if header.kind != ET_DYN {
+        return Err("not a position-independent executable");
+    }
+    if (header.program_header_size as usize) < size_of::<ProgramHeader>() {
+        return Err("bad program header size");
+    }
+    let segments = (0..header.program_header_count as u64)
+        .map(|i| {
+            let offset = header.program_header_offset + i * header.program_header_size as u64;
+            read::<ProgramHeader>(image, offset)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    //- Copy the segments
+    let mut start = u64::MAX;
+    let mut end = 0;
+    for segment in segments.iter().filter(|segment| segment.kind == PT_LOAD) {
+        let segment_end = segment
+            .vaddr
+            .checked_add(segment.memory_size)
+            .ok_or("segment out of bounds")?;
+        if segment.file_size > segment.memory_size {
+            return Err("segment larger in the file than in memory");
+        }
+        range(image, segment.offset, segment.file_size)?;
+        start = start.min(segment.vaddr);
+        end = end.max(segment_end);
+    }
+    if start >= end {
+        return Err("no loadable segment");
+    }
+    if end > size {
+        return Err("program too large");
+    }
+    // zeroes the parts of the segments that are not in the file
+    space.map_user(base + start, end - start)?;
+    for segment in segments.iter().filter(|segment| segment.kind == PT_LOAD) {
+        let file = range(image, segment.offset, segment.file_size)?;
+        space.write(base + segment.vaddr, &image[file])?;
+    }
+
+    //- Relocate
+    if let Some(dynamic) = segments.iter().find(|segment| segment.kind == PT_DYNAMIC) {
+        let (mut rela, mut rela_size, mut rela_entry) = (None, 0, size_of::<Rela>() as u64);
+        for i in 0..dynamic.file_size / size_of::<Dynamic>() as u64 {
+            let entry: Dynamic = read(image, dynamic.offset + i * size_of::<Dynamic>() as u64)?;
+            match entry.tag {
+                DT_NULL => break,
+                DT_RELA => rela = Some(entry.value),
+                DT_RELASZ => rela_size = entry.value,
+                DT_RELAENT => rela_entry = entry.value,
+                _ => {}
+            }
+        }
+        if let Some(rela) = rela {
+            if rela_entry < size_of::<Rela>() as u64 {
+                return Err("bad relocation entry size");
+            }
+            if rela < start || rela.saturating_add(rela_size) > end {
+                return Err("relocations out of bounds");
+            }
+            for i in 0..rela_size / rela_entry {
+                let relocation = read_relocation(space, base + rela + i * rela_entry)?;
+                if relocation.info & 0xffff_ffff != R_X86_64_RELATIVE {
+                    return Err("unsupported relocation type");
+                }
+                if relocation.offset < start || relocation.offset.saturating_add(8) > end {
+                    return Err("relocation out of bounds");
+                }
+                let value = base.as_u64().wrapping_add(relocation.addend as u64);
+                space.write(base + relocation.offset, &value.to_le_bytes())?;
+            }
+        }
+    }
+
+    if !(start..end).contains(&header.entry) {
+        return Err("entry point out of bounds");
+    }
+    Ok(base + header.entry)
+}
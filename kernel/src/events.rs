@@ -0,0 +1,127 @@
+use crate::task::executor;
+use crate::thread::{self, ThreadId};
+use crate::{handlers, timers};
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use pc_keyboard::DecodedKey;
+use spin::Once;
+use x86_64::instructions::interrupts;
+
+/// Events the queue can hold before new ones are dropped
+const CAPACITY: usize = 256;
+
+static QUEUE: EventQueue = EventQueue::new();
+/// Thread running `run`, parked while there is nothing to do
+static LOOP_THREAD: Once<ThreadId> = Once::new();
+
+/// Something an interrupt handler observed, to be handled outside the interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// A timer interrupt, with the tick count it brought `time::ticks` to
+    Tick(u64),
+    /// A key decoded by the keyboard interrupt
+    Key(DecodedKey),
+    /// A byte received on COM1
+    Serial(u8),
+}
+
+/// Lock-free ring buffer. Interrupt handlers push and the cpu loop pops. Interrupt handlers do
+/// not nest, so there is only ever one producer and one consumer at a time.
+struct EventQueue {
+    slots: [UnsafeCell<Event>; CAPACITY],
+    /// Index of the next event to pop, wrapping
+    head: AtomicUsize,
+    /// Index of the next event to push, wrapping
+    tail: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+unsafe impl Sync for EventQueue {}
+
+impl EventQueue {
+    const fn new() -> Self {
+        EventQueue {
+            slots: [const { UnsafeCell::new(Event::Tick(0)) }; CAPACITY],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    fn push(&self, event: Event) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) == CAPACITY {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        // the slot is free, and only the producer writes to free slots
+        unsafe { *self.slots[tail % CAPACITY].get() = event };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    fn pop(&self) -> Option<Event> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        // the slot is full, and the producer does not touch full slots
+        let event = unsafe { *self.slots[head % CAPACITY].get() };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(event)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Relaxed) == self.tail.load(Ordering::Acquire)
+    }
+}
+
+/// Queues an event. Called by the interrupt handlers; the event is dropped if the queue is full.
+pub(crate) fn push(event: Event) {
+    QUEUE.push(event);
+    wake_loop();
+}
+
+/// Wakes the thread running `run` if it is parked, for example because a task was woken.
+pub(crate) fn wake_loop() {
+    if let Some(&id) = LOOP_THREAD.get() {
+        thread::unpark(id);
+    }
+}
+
+/// Number of events dropped because the queue was full.
+pub fn dropped() -> u64 {
+    QUEUE.dropped.load(Ordering::Relaxed)
+}
+
+/// Hands every queued event to the timers and handlers registered for it. A `cpu_loop` other
+/// than the default must call this regularly, or no handler will run.
+pub fn dispatch_pending() {
+    while let Some(event) = QUEUE.pop() {
+        match event {
+            Event::Tick(now) => {
+                timers::run(now);
+                handlers::handle_timer();
+            }
+            Event::Key(key) => handlers::handle_keyboard(key),
+            Event::Serial(byte) => handlers::handle_serial(byte),
+        }
+    }
+}
+
+/// The default cpu loop: dispatches events as they come in, polls the async tasks they woke,
+/// and parks its thread while there is nothing to do, leaving the CPU to the other threads.
+pub fn run() -> ! {
+    LOOP_THREAD.call_once(thread::current);
+    loop {
+        dispatch_pending();
+        executor::run_ready();
+        // check with interrupts off, so that an event arriving in between unparks the thread
+        // only once it is parked
+        interrupts::disable();
+        if QUEUE.is_empty() && !executor::has_ready() {
+            thread::park();
+        }
+        interrupts::enable();
+    }
+}
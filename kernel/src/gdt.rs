@@ -0,0 +1,88 @@
+use crate::RacyCell;
+use core::ptr;
+use lazy_static::lazy_static;
+use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
+use x86_64::instructions::tables::load_tss;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+/// Interrupt Stack Table slots. Exceptions using them switch to a known good stack, so they
+/// can still be reported when the kernel stack has overflowed.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+const IST_STACK_SIZE: usize = 4096 * 5;
+
+static mut DOUBLE_FAULT_STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+static mut NMI_STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+static mut MACHINE_CHECK_STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+
+/// Returns the address just past the end of the stack, where the CPU starts pushing.
+/// The CPU aligns it to 16 bytes itself when switching stacks.
+fn stack_top(stack: *const [u8; IST_STACK_SIZE]) -> VirtAddr {
+    VirtAddr::from_ptr(stack) + IST_STACK_SIZE
+}
+
+struct Selectors {
+    code: SegmentSelector,
+    data: SegmentSelector,
+    user_code: SegmentSelector,
+    user_data: SegmentSelector,
+    tss: SegmentSelector,
+}
+
+/// The TSS is changed after it is loaded, to set the stack used when an interrupt arrives in
+/// ring 3. Only the running thread does that, with interrupts disabled.
+static TSS: RacyCell<TaskStateSegment> = RacyCell::new(TaskStateSegment::new());
+
+lazy_static! {
+    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+        let mut gdt = GlobalDescriptorTable::new();
+        let code = gdt.add_entry(Descriptor::kernel_code_segment());
+        let data = gdt.add_entry(Descriptor::kernel_data_segment());
+        let user_data = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code = gdt.add_entry(Descriptor::user_code_segment());
+        let tss = gdt.add_entry(Descriptor::tss_segment(unsafe { TSS.get_mut() }));
+        let selectors = Selectors {
+            code,
+            data,
+            user_code,
+            user_data,
+            tss,
+        };
+        (gdt, selectors)
+    };
+}
+
+/// Loads the kernel's own GDT and TSS, replacing the ones set up by the bootloader.
+pub fn init() {
+    let tss = unsafe { TSS.get_mut() };
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        stack_top(ptr::addr_of!(DOUBLE_FAULT_STACK));
+    tss.interrupt_stack_table[NMI_IST_INDEX as usize] = stack_top(ptr::addr_of!(NMI_STACK));
+    tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] =
+        stack_top(ptr::addr_of!(MACHINE_CHECK_STACK));
+
+    let (gdt, selectors) = &*GDT;
+    gdt.load();
+    unsafe {
+        CS::set_reg(selectors.code);
+        SS::set_reg(selectors.data);
+        DS::set_reg(selectors.data);
+        ES::set_reg(selectors.data);
+        load_tss(selectors.tss);
+    }
+}
+
+/// Code and stack segment selectors for ring 3.
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    let (_, selectors) = &*GDT;
+    (selectors.user_code, selectors.user_data)
+}
+
+/// Sets the stack the CPU switches to when an interrupt or syscall arrives in ring 3.
+pub fn set_kernel_stack(top: VirtAddr) {
+    unsafe { TSS.get_mut().privilege_stack_table[0] = top };
+}
@@ -0,0 +1,157 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use pc_keyboard::DecodedKey;
+use spin::Mutex;
+
+pub(crate) type TimerHandler = Box<dyn FnMut() + Send>;
+pub(crate) type KeyboardHandler = Box<dyn FnMut(DecodedKey) + Send>;
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    next_id: 0,
+    timer: Vec::new(),
+    keyboard: Vec::new(),
+    serial: Vec::new(),
+});
+
+/// Handle to a registered handler, used to remove it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId(u64);
+
+/// A registered handler. The closure is taken out while it runs, so that the registry is not
+/// locked during the call.
+struct Entry<F: ?Sized> {
+    id: HandlerId,
+    handler: Option<Box<F>>,
+}
+
+struct Registry {
+    next_id: u64,
+    timer: Vec<Entry<dyn FnMut() + Send>>,
+    keyboard: Vec<Entry<dyn FnMut(DecodedKey) + Send>>,
+    serial: Vec<Entry<dyn FnMut(u8) + Send>>,
+}
+
+impl Registry {
+    /// Ids start at 1, so that 0 can mean "before every handler" in `dispatch`.
+    fn next_id(&mut self) -> HandlerId {
+        self.next_id += 1;
+        HandlerId(self.next_id)
+    }
+}
+
+/// Runs `f` on the registry with interrupts disabled, so an interrupt cannot deadlock on it.
+fn with_registry<R>(f: impl FnOnce(&mut Registry) -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| f(&mut REGISTRY.lock()))
+}
+
+/// Adds a handler called for every timer interrupt, after the `timers` callbacks.
+pub fn on_timer(handler: impl FnMut() + Send + 'static) -> HandlerId {
+    add_timer(Box::new(handler))
+}
+
+/// Adds a handler called with every key decoded by the keyboard interrupt.
+/// Handlers run from the cpu loop, see `events::dispatch_pending`.
+pub fn on_keyboard(handler: impl FnMut(DecodedKey) + Send + 'static) -> HandlerId {
+    add_keyboard(Box::new(handler))
+}
+
+/// Adds a handler called with every byte received on COM1.
+/// Handlers run from the cpu loop, see `events::dispatch_pending`.
+pub fn on_serial(handler: impl FnMut(u8) + Send + 'static) -> HandlerId {
+    with_registry(|registry| {
+        let id = registry.next_id();
+        registry.serial.push(Entry {
+            id,
+            handler: Some(Box::new(handler)),
+        });
+        id
+    })
+}
+
+pub(crate) fn add_timer(handler: TimerHandler) -> HandlerId {
+    with_registry(|registry| {
+        let id = registry.next_id();
+        registry.timer.push(Entry {
+            id,
+            handler: Some(handler),
+        });
+        id
+    })
+}
+
+pub(crate) fn add_keyboard(handler: KeyboardHandler) -> HandlerId {
+    with_registry(|registry| {
+        let id = registry.next_id();
+        registry.keyboard.push(Entry {
+            id,
+            handler: Some(handler),
+        });
+        id
+    })
+}
+
+/// Removes a timer, keyboard or serial handler. Returns false if it was not registered. A handler can
+/// remove itself while it runs.
+pub fn remove(id: HandlerId) -> bool {
+    let (removed_timer, removed_keyboard, removed_serial) = with_registry(|registry| {
+        (
+            take_entry(&mut registry.timer, id),
+            take_entry(&mut registry.keyboard, id),
+            take_entry(&mut registry.serial, id),
+        )
+    });
+    // dropped outside the lock, in case what the closure owns touches handlers when dropped
+    removed_timer.is_some() || removed_keyboard.is_some() || removed_serial.is_some()
+}
+
+fn take_entry<F: ?Sized>(entries: &mut Vec<Entry<F>>, id: HandlerId) -> Option<Entry<F>> {
+    let i = entries.iter().position(|entry| entry.id == id)?;
+    Some(entries.remove(i))
+}
+
+/// Calls every handler in the list picked by `list`, in registration order, with the registry
+/// unlocked during each call. Entries are kept in increasing id order, so the loop can find its
+/// place again when handlers are added or removed meanwhile.
+fn dispatch<F: ?Sized>(
+    list: fn(&mut Registry) -> &mut Vec<Entry<F>>,
+    mut call: impl FnMut(&mut F),
+) {
+    let mut last = 0;
+    while let Some((id, handler)) = with_registry(|registry| {
+        let entry = list(registry).iter_mut().find(|entry| entry.id.0 > last)?;
+        Some((entry.id, entry.handler.take()))
+    }) {
+        last = id.0;
+        // a handler that is already running, because it dispatched events itself, is skipped
+        let Some(mut handler) = handler else {
+            continue;
+        };
+        call(&mut handler);
+        // put it back, unless the handler was removed while it ran
+        let removed = with_registry(|registry| {
+            match list(registry).iter_mut().find(|entry| entry.id == id) {
+                Some(entry) => {
+                    entry.handler = Some(handler);
+                    None
+                }
+                None => Some(handler),
+            }
+        });
+        drop(removed);
+    }
+}
+
+/// Called for each timer event.
+pub(crate) fn handle_timer() {
+    dispatch(|registry| &mut registry.timer, |handler| handler());
+}
+
+/// Called for each keyboard event.
+pub(crate) fn handle_keyboard(key: DecodedKey) {
+    dispatch(|registry| &mut registry.keyboard, |handler| handler(key));
+}
+
+/// Called for each serial event.
+pub(crate) fn handle_serial(byte: u8) {
+    dispatch(|registry| &mut registry.serial, |handler| handler(byte));
+}
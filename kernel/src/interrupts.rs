@@ -1,46 +1,282 @@
-use crate::serial;
-use crate::HandlerTable;
-use core::fmt::Write;
+use crate::events::{self, Event};
+use crate::{
+    apic, crash_screen, end_crashed_run, gdt, process, serial, serial_println, syscall, testing,
+    thread, time, QemuExitCode,
+};
+use core::fmt::{self, Write};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin::Mutex;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::registers::control::Cr2;
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::{PrivilegeLevel, VirtAddr};
 
 // This code is largely Copyright (c) 2019 Philipp Oppermann.
 // Gabriel Ferrer added:
 // - HANDLERS variable.
 // - Use of HANDLERS in init_idt, timer_interrupt_handler, keyboard_interrupt_handler
 
-lazy_static! {
-    static ref HANDLERS: Mutex<Option<HandlerTable>> = Mutex::new(None);
-}
+// HANDLERS has since moved to the handlers module.
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.debug.set_handler_fn(debug_handler);
+        unsafe {
+            idt.non_maskable_interrupt
+                .set_handler_fn(non_maskable_interrupt_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
+        }
         idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.double_fault.set_handler_fn(double_fault_handler);
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
+        idt.overflow.set_handler_fn(overflow_handler);
+        idt.bound_range_exceeded
+            .set_handler_fn(bound_range_exceeded_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.device_not_available
+            .set_handler_fn(device_not_available_handler);
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(double_fault_handler)
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+        idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+        idt.segment_not_present
+            .set_handler_fn(segment_not_present_handler);
+        idt.stack_segment_fault
+            .set_handler_fn(stack_segment_fault_handler);
+        idt.general_protection_fault
+            .set_handler_fn(general_protection_fault_handler);
+        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.x87_floating_point
+            .set_handler_fn(x87_floating_point_handler);
+        idt.alignment_check.set_handler_fn(alignment_check_handler);
+        unsafe {
+            idt.machine_check
+                .set_handler_fn(machine_check_handler)
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+        }
+        idt.simd_floating_point
+            .set_handler_fn(simd_floating_point_handler);
+        idt.virtualization.set_handler_fn(virtualization_handler);
+        idt.cp_protection_exception
+            .set_handler_fn(cp_protection_exception_handler);
+        idt.hv_injection_exception
+            .set_handler_fn(hv_injection_exception_handler);
+        idt.vmm_communication_exception
+            .set_handler_fn(vmm_communication_exception_handler);
+        idt.security_exception
+            .set_handler_fn(security_exception_handler);
+        // the timer and yield entries save and restore registers themselves to switch threads
+        unsafe {
+            idt[InterruptIndex::Timer.as_usize()]
+                .set_handler_addr(VirtAddr::new(thread::timer_entry as *const () as u64));
+            idt[thread::YIELD_VECTOR as usize]
+                .set_handler_addr(VirtAddr::new(thread::yield_entry as *const () as u64));
+            idt[syscall::SYSCALL_VECTOR as usize]
+                .set_handler_addr(VirtAddr::new(syscall::syscall_entry as *const () as u64))
+                .set_privilege_level(PrivilegeLevel::Ring3);
+        }
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Serial.as_usize()].set_handler_fn(serial_interrupt_handler);
+        idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
 
-/// Initializes the interrupt table with the given interrupt handlers.
-pub fn init_idt(handlers: HandlerTable) {
-    *(HANDLERS.lock()) = Some(handlers);
+/// Loads the interrupt table.
+pub fn init_idt() {
     IDT.load();
 }
 
+/// Formats the CPU state saved in an interrupt stack frame, one register per line.
+struct DecodedStackFrame<'a>(&'a InterruptStackFrame);
+
+impl fmt::Display for DecodedStackFrame<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "RIP:    {:#018x}", self.0.instruction_pointer.as_u64())?;
+        writeln!(f, "CS:     {:#06x}", self.0.code_segment)?;
+        writeln!(
+            f,
+            "RFLAGS: {:#x} {:?}",
+            self.0.cpu_flags,
+            RFlags::from_bits_truncate(self.0.cpu_flags)
+        )?;
+        writeln!(f, "RSP:    {:#018x}", self.0.stack_pointer.as_u64())?;
+        write!(f, "SS:     {:#06x}", self.0.stack_segment)
+    }
+}
+
+/// Formats the error code pushed by exceptions that refer to a segment selector.
+struct SelectorErrorCode(u64);
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "Error code: 0");
+        }
+        let table = match (self.0 >> 1) & 0b11 {
+            0 => "GDT",
+            2 => "LDT",
+            _ => "IDT",
+        };
+        write!(
+            f,
+            "Error code: {:#x} (selector index {} in the {table}{})",
+            self.0,
+            (self.0 >> 3) & 0x1fff,
+            if self.0 & 1 != 0 { ", external" } else { "" }
+        )
+    }
+}
+
+/// Reports an exception that is not fatal on serial and returns to the interrupted code.
+/// The exception may have interrupted a log line or a print, so it skips the logger and does not
+/// wait for the port, whose locks the interrupted code may hold.
+fn report_exception(name: &str, stack_frame: &InterruptStackFrame) {
+    serial::with_writer_nowait(|serial| {
+        let _ = writeln!(
+            serial,
+            "EXCEPTION: {name}\n{}",
+            DecodedStackFrame(stack_frame)
+        );
+    });
+}
+
+/// Reports an exception the kernel cannot recover from on serial and on the crash screen,
+/// then ends the run with `end_crashed_run`. An exception caused by a user program only ends that program.
+fn fatal_exception(name: &str, stack_frame: &InterruptStackFrame, details: fmt::Arguments) -> ! {
+    x86_64::instructions::interrupts::disable();
+    process::kill_on_exception(name, stack_frame.code_segment);
+    let stack_frame = DecodedStackFrame(stack_frame);
+    // the exception may have interrupted a print, and the kernel does not go back to it
+    unsafe { serial::force_unlock() };
+    testing::on_fatal_exception(format_args!("EXCEPTION: {name}\n{details}\n{stack_frame}"));
+    serial_println!("EXCEPTION: {name}\n{details}\n{stack_frame}");
+    crash_screen::show(
+        "EXCEPTION",
+        format_args!("{name}\n{details}\n\n{stack_frame}"),
+    );
+    end_crashed_run(QemuExitCode::Failed);
+}
+
+/// Defines a handler that reports an exception without error code as fatal.
+macro_rules! fatal_handler {
+    ($handler:ident, $name:literal) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame) {
+            fatal_exception($name, &stack_frame, format_args!(""));
+        }
+    };
+}
+
+/// Defines a handler that reports an exception with a selector error code as fatal.
+macro_rules! fatal_selector_handler {
+    ($handler:ident, $name:literal) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame, error_code: u64) {
+            fatal_exception(
+                $name,
+                &stack_frame,
+                format_args!("{}", SelectorErrorCode(error_code)),
+            );
+        }
+    };
+}
+
+fatal_handler!(divide_error_handler, "DIVIDE ERROR");
+fatal_handler!(overflow_handler, "OVERFLOW");
+fatal_handler!(bound_range_exceeded_handler, "BOUND RANGE EXCEEDED");
+fatal_handler!(invalid_opcode_handler, "INVALID OPCODE");
+fatal_handler!(device_not_available_handler, "DEVICE NOT AVAILABLE");
+fatal_handler!(x87_floating_point_handler, "x87 FLOATING POINT");
+fatal_handler!(simd_floating_point_handler, "SIMD FLOATING POINT");
+fatal_handler!(virtualization_handler, "VIRTUALIZATION");
+fatal_handler!(hv_injection_exception_handler, "HYPERVISOR INJECTION");
+fatal_selector_handler!(invalid_tss_handler, "INVALID TSS");
+fatal_selector_handler!(segment_not_present_handler, "SEGMENT NOT PRESENT");
+fatal_selector_handler!(stack_segment_fault_handler, "STACK SEGMENT FAULT");
+fatal_selector_handler!(general_protection_fault_handler, "GENERAL PROTECTION FAULT");
+
+extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    report_exception("DEBUG", &stack_frame);
+}
+
+extern "x86-interrupt" fn non_maskable_interrupt_handler(stack_frame: InterruptStackFrame) {
+    report_exception("NON-MASKABLE INTERRUPT", &stack_frame);
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    writeln!(serial(), "EXCEPTION: BREAKPOINT\n{:#?}", stack_frame).unwrap();
+    report_exception("BREAKPOINT", &stack_frame);
 }
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    fatal_exception("DOUBLE FAULT", &stack_frame, format_args!(""));
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    fatal_exception(
+        "PAGE FAULT",
+        &stack_frame,
+        format_args!(
+            "Accessed address (CR2): {:#018x}\nError code: {:?}",
+            Cr2::read().as_u64(),
+            error_code
+        ),
+    );
+}
+
+extern "x86-interrupt" fn alignment_check_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    fatal_exception(
+        "ALIGNMENT CHECK",
+        &stack_frame,
+        format_args!("Error code: {error_code:#x}"),
+    );
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    fatal_exception("MACHINE CHECK", &stack_frame, format_args!(""));
+}
+
+extern "x86-interrupt" fn cp_protection_exception_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    fatal_exception(
+        "CONTROL PROTECTION",
+        &stack_frame,
+        format_args!("Error code: {error_code:#x}"),
+    );
+}
+
+extern "x86-interrupt" fn vmm_communication_exception_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    fatal_exception(
+        "VMM COMMUNICATION",
+        &stack_frame,
+        format_args!("Error code: {error_code:#x}"),
+    );
+}
+
+extern "x86-interrupt" fn security_exception_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    fatal_exception(
+        "SECURITY EXCEPTION",
+        &stack_frame,
+        format_args!("Error code: {error_code:#x}"),
+    );
 }
 
 const PIC_1_OFFSET: u8 = 32;
@@ -51,13 +287,15 @@ pub static PICS: Mutex<ChainedPics> =
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
-enum InterruptIndex {
+pub(crate) enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    /// COM1, IRQ 4
+    Serial = PIC_1_OFFSET + 4,
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub(crate) fn as_u8(self) -> u8 {
         self as u8
     }
 
@@ -66,17 +304,36 @@ impl InterruptIndex {
     }
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    let h = &*HANDLERS.lock();
-    if let Some(handler) = h {
-        handler.handle_timer();
-    }
+/// Remaps the 8259 PIC above the exceptions and unmasks the serial port, which the firmware
+/// may have left masked.
+pub(crate) fn init_pics() {
+    let mut pics = PICS.lock();
     unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+        pics.initialize();
+        let [primary, secondary] = pics.read_masks();
+        let serial = InterruptIndex::Serial.as_u8() - PIC_1_OFFSET;
+        pics.write_masks(primary & !(1 << serial), secondary);
+    }
+}
+
+/// Acknowledges the interrupt to whichever controller delivered it.
+fn end_of_interrupt(index: InterruptIndex) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(index.as_u8()) };
     }
 }
 
+/// Called by `thread::timer_entry` with the stack pointer of the interrupted thread, returns
+/// the stack pointer of the thread to resume.
+pub(crate) extern "C" fn timer_interrupt(rsp: u64) -> u64 {
+    time::tick();
+    events::push(Event::Tick(time::ticks()));
+    end_of_interrupt(InterruptIndex::Timer);
+    thread::switch(rsp)
+}
+
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
     use pc_keyboard::{layouts, HandleControl, Keyboard, ScancodeSet1};
     use x86_64::instructions::port::Port;
@@ -93,15 +350,23 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     let scancode: u8 = unsafe { port.read() };
     if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
         if let Some(key) = keyboard.process_keyevent(key_event) {
-            let h = &*HANDLERS.lock();
-            if let Some(handler) = h {
-                handler.handle_keyboard(key);
-            }
+            events::push(Event::Key(key));
         }
     }
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+    end_of_interrupt(InterruptIndex::Keyboard);
 }
+
+/// Queues every byte COM1 has received.
+extern "x86-interrupt" fn serial_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    serial::with_port(|port| {
+        while let Ok(byte) = port.try_receive() {
+            events::push(Event::Serial(byte));
+        }
+    });
+
+    end_of_interrupt(InterruptIndex::Serial);
+}
+
+/// The local APIC does not expect an end of interrupt for spurious interrupts.
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
@@ -1,22 +1,57 @@
 // Original code from rust-osdev/bootloader crate https://github.com/rust-osdev/bootloader
 
 #![no_std]
+#![cfg_attr(test, no_main)]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 
+pub mod allocator;
+mod apic;
+pub mod boot_flags;
+pub mod crash_screen;
+mod elf;
+pub mod events;
+mod gdt;
+pub mod handlers;
 mod interrupts;
+pub mod logger;
+pub mod memory;
+pub mod process;
+pub mod ramdisk;
+pub mod serial;
+pub mod shell;
+pub mod syscall;
+pub mod task;
+pub mod telemetry;
+pub mod testing;
+pub mod thread;
+pub mod time;
+pub mod timers;
 
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use bootloader_api::config::Mapping::Dynamic;
+use bootloader_api::BootloaderConfig;
 use core::cell::UnsafeCell;
-use core::fmt::Write;
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
 use pc_keyboard::DecodedKey;
-use uart_16550::SerialPort;
 extern crate alloc;
 
-pub fn serial() -> SerialPort {
-    let mut port = unsafe { SerialPort::new(0x3F8) };
-    port.init();
-    port
-}
+/// Bootloader settings the kernel relies on: `memory` needs all physical memory mapped, and the
+/// heap and the other ranges of the kernel live in the lower half. Pass it to `entry_point!`.
+pub const BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Dynamic); // obtain physical memory offset
+    config.kernel_stack_size = 1024 * 1024; // 1 MB stack
+
+    // keep the bootloader's mappings in the higher half, the lower half is ours (heap)
+    config.mappings.dynamic_range_start = Some(0xffff_8000_0000_0000);
+    config
+};
 
 /// Table of interrupt handlers. This struct uses the
 /// [Builder pattern](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
@@ -27,10 +62,22 @@ pub fn serial() -> SerialPort {
 /// For now, it only includes timer and keyboard handlers.
 /// I will add more if it seems useful to do so.
 /// Double-fault handling is addressed "behind the scenes".
+///
+/// Handlers can be closures, and there can be several per event. More can be added, and any
+/// removed, after **.start()** through the [handlers] module.
+///
+/// Handlers do not run inside the interrupt: the interrupt queues an [events::Event], and the
+/// cpu loop calls the handlers for it with interrupts enabled.
+///
+/// Once started, the timer also switches between the threads started with [thread::spawn].
 pub struct HandlerTable {
-    timer: Option<fn()>,
-    keyboard: Option<fn(DecodedKey)>,
-    startup: Option<fn()>,
+    timer: Vec<handlers::TimerHandler>,
+    timer_frequency: Option<u32>,
+    rsdp_addr: Option<u64>,
+    keyboard: Vec<handlers::KeyboardHandler>,
+    startup: Option<Box<dyn FnOnce()>>,
+    screen: Option<Box<dyn syscall::Screen>>,
+    ramdisk: Option<&'static [u8]>,
     cpu_loop: fn() -> !,
 }
 
@@ -38,66 +85,121 @@ impl HandlerTable {
     /// Creates a new HandlerTable with no handlers.
     pub fn new() -> Self {
         HandlerTable {
-            timer: None,
-            keyboard: None,
+            timer: Vec::new(),
+            timer_frequency: None,
+            rsdp_addr: None,
+            keyboard: Vec::new(),
             startup: None,
-            cpu_loop: hlt_loop,
+            screen: None,
+            ramdisk: None,
+            cpu_loop: events::run,
         }
     }
 
     /// Starts up a simple operating system using the specified handlers.
     pub fn start(self) -> ! {
+        gdt::init();
+        time::calibrate_tsc();
         if let Some(f) = self.startup {
             f()
         }
         let fore = self.cpu_loop;
+        let timer_frequency = self.timer_frequency;
+        let rsdp_addr = self.rsdp_addr;
+        for handler in self.timer {
+            handlers::add_timer(handler);
+        }
+        for handler in self.keyboard {
+            handlers::add_keyboard(handler);
+        }
 
-        interrupts::init_idt(self);
-        unsafe { interrupts::PICS.lock().initialize() };
+        if let Some(image) = self.ramdisk {
+            if let Err(error) = ramdisk::init(image) {
+                log::warn!("ramdisk ignored ({error})");
+            }
+        }
+        thread::init();
+        syscall::init(self.screen);
+        shell::init();
+        interrupts::init_idt();
+        interrupts::init_pics();
+        if let Some(rsdp_addr) = rsdp_addr {
+            if let Err(error) = unsafe { apic::init(rsdp_addr, timer_frequency) } {
+                log::warn!("APIC unavailable ({error}), using the 8259 PIC");
+            }
+        }
+        if !apic::is_enabled() {
+            if let Some(hz) = timer_frequency {
+                time::set_frequency(hz);
+            }
+        }
         x86_64::instructions::interrupts::enable();
 
         (fore)();
     }
 
-    /// Sets the timer handler.
+    /// Adds a timer handler, called for every timer interrupt. Callbacks at other rates can be
+    /// registered with the `timers` module.
     /// Returns Self for chained [Builder pattern construction](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
-    pub fn timer(mut self, timer_handler: fn()) -> Self {
-        self.timer = Some(timer_handler);
+    pub fn timer(mut self, timer_handler: impl FnMut() + Send + 'static) -> Self {
+        self.timer.push(Box::new(timer_handler));
         self
     }
 
-    /// Called by the low-level interrupt routines to handle a timer event.
-    pub fn handle_timer(&self) {
-        if let Some(timer) = self.timer {
-            (timer)()
-        }
+    /// Sets how many timer interrupts fire per second. Without it, the timer keeps the
+    /// firmware's default of about 18.2 Hz.
+    /// Returns Self for chained [Builder pattern construction](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
+    pub fn timer_frequency(mut self, hz: u32) -> Self {
+        self.timer_frequency = Some(hz);
+        self
     }
 
-    /// Sets the keyboard handler. The [DecodedKey](https://docs.rs/pc-keyboard/0.5.1/pc_keyboard/enum.DecodedKey.html)
+    /// Delivers interrupts through the local APIC and the IO-APIC instead of the 8259 PIC, with
+    /// the local APIC timer as the timer. `rsdp_addr` is the physical address of the ACPI RSDP,
+    /// as found in `BootInfo::rsdp_addr`, and `kernel::memory::init` must have been called.
+    /// If the APIC cannot be set up, the PIC is used after all.
+    /// Returns Self for chained [Builder pattern construction](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
+    pub fn apic(mut self, rsdp_addr: u64) -> Self {
+        self.rsdp_addr = Some(rsdp_addr);
+        self
+    }
+
+    /// Adds a keyboard handler. The [DecodedKey](https://docs.rs/pc-keyboard/0.5.1/pc_keyboard/enum.DecodedKey.html)
     /// enum comes from the [pc_keyboard](https://crates.io/crates/pc-keyboard) crate.
     ///
     /// Returns Self for chained [Builder pattern construction](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
-    pub fn keyboard(mut self, keyboard_handler: fn(DecodedKey)) -> Self {
-        self.keyboard = Some(keyboard_handler);
+    pub fn keyboard(mut self, keyboard_handler: impl FnMut(DecodedKey) + Send + 'static) -> Self {
+        self.keyboard.push(Box::new(keyboard_handler));
         self
     }
 
-    /// Called by the low-level interrupt routines to handle a keyboard event.
-    pub fn handle_keyboard(&self, key: DecodedKey) {
-        if let Some(keyboard) = self.keyboard {
-            (keyboard)(key)
-        }
+    /// Sets the screen user programs draw on through syscalls. Without it, drawing syscalls fail.
+    /// Returns Self for chained [Builder pattern construction](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
+    pub fn screen(mut self, screen: impl syscall::Screen + 'static) -> Self {
+        self.screen = Some(Box::new(screen));
+        self
+    }
+
+    /// Sets the ramdisk the bootloader loaded, as found in `BootInfo::ramdisk_addr` and
+    /// `BootInfo::ramdisk_len`. Its programs can then be started with
+    /// [process::spawn_program].
+    /// Returns Self for chained [Builder pattern construction](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
+    pub fn ramdisk(mut self, ramdisk: &'static [u8]) -> Self {
+        self.ramdisk = Some(ramdisk);
+        self
     }
 
     /// Sets the startup handler.
     /// Returns Self for chained [Builder pattern construction](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
-    pub fn startup(mut self, startup_handler: fn()) -> Self {
-        self.startup = Some(startup_handler);
+    pub fn startup(mut self, startup_handler: impl FnOnce() + 'static) -> Self {
+        self.startup = Some(Box::new(startup_handler));
         self
     }
 
     /// Sets the cpu loop handler.
-    /// This function should contain an infinite loop.
+    /// This function should contain an infinite loop, and call `events::dispatch_pending`
+    /// for the other handlers to run, and `task::executor::run_ready` for async tasks.
+    /// The default is `events::run`.
     /// Returns Self for chained [Builder pattern construction](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
     pub fn cpu_loop(mut self, cpu_loop: fn() -> !) -> Self {
         self.cpu_loop = cpu_loop;
@@ -117,10 +219,70 @@ pub fn hlt_loop() -> ! {
     }
 }
 
+/// I/O port of QEMU's isa-debug-exit device, as passed to `-device isa-debug-exit`
+const ISA_DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// Exit codes reported to QEMU through the isa-debug-exit device.
+/// QEMU terminates with the status `(code << 1) | 1`, which the runner turns back into the code,
+/// or into 0 for `Success`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    /// A panic or an exception the kernel cannot recover from
+    Failed = 0x11,
+    OutOfMemory = 0x31,
+}
+
+/// Ends the QEMU run with the given exit code. On machines without the isa-debug-exit device
+/// this just halts the CPU.
+pub fn exit_qemu(exit_code: QemuExitCode) -> ! {
+    use x86_64::instructions::port::Port;
+
+    unsafe {
+        let mut port = Port::new(ISA_DEBUG_EXIT_PORT);
+        port.write(exit_code as u32);
+    }
+    hlt_loop();
+}
+
+/// Ends a run after a panic, an exception or an allocation the kernel cannot recover from. QEMU
+/// exits with `code` while tests run or when the host passed the `exit-on-panic` boot flag, as
+/// CI does. Otherwise the CPU halts, leaving the crash screen up.
+pub(crate) fn end_crashed_run(code: QemuExitCode) -> ! {
+    if testing::is_running() || boot_flags::is_set("exit-on-panic") {
+        exit_qemu(code);
+    }
+    hlt_loop();
+}
+
+/// Set by the first panic, so that a panic while reporting a panic does not recurse.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    let _ = writeln!(serial(), "PANIC: {info}");
-    hlt_loop();
+    x86_64::instructions::interrupts::disable();
+    let registers = crash_screen::RegisterDump::capture();
+    // the panic may have interrupted a print, and nothing will run after this handler
+    unsafe { serial::force_unlock() };
+    testing::on_panic(info);
+    serial_println!("PANIC: {info}\n{registers}");
+    if !PANICKING.swap(true, Ordering::SeqCst) {
+        match info.location() {
+            Some(location) => crash_screen::show(
+                "PANIC",
+                format_args!(
+                    "{}\n\nat {}:{}:{}\n\n{registers}",
+                    info.message(),
+                    location.file(),
+                    location.line(),
+                    location.column()
+                ),
+            ),
+            None => crash_screen::show("PANIC", format_args!("{}\n\n{registers}", info.message())),
+        }
+    }
+    end_crashed_run(QemuExitCode::Failed);
 }
 
 pub struct RacyCell<T>(UnsafeCell<T>);
@@ -142,3 +304,47 @@ impl<T> RacyCell<T> {
 
 unsafe impl<T> Send for RacyCell<T> where T: Send {}
 unsafe impl<T: Sync> Sync for RacyCell<T> {}
+
+#[cfg(test)]
+bootloader_api::entry_point!(test_kernel_main, config = &BOOTLOADER_CONFIG);
+
+/// Entry point of the library's test binary
+#[cfg(test)]
+fn test_kernel_main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    testing::boot(boot_info);
+    test_main();
+    hlt_loop();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RacyCell;
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn racy_cell_keeps_writes() {
+        static COUNTER: RacyCell<u32> = RacyCell::new(0);
+        unsafe {
+            *COUNTER.get_mut() += 1;
+            *COUNTER.get_mut() += 1;
+            assert_eq!(*COUNTER.get_mut(), 2);
+        }
+    }
+
+    #[test_case]
+    fn racy_cell_holds_heap_values() {
+        static NAMES: RacyCell<Vec<&str>> = RacyCell::new(Vec::new());
+        unsafe {
+            NAMES.get_mut().push("player");
+            NAMES.get_mut().push("enemy");
+            assert_eq!(NAMES.get_mut().as_slice(), ["player", "enemy"]);
+        }
+    }
+
+    #[test_case]
+    fn racy_cell_gives_the_value_back() {
+        let cell = RacyCell::new(Some(3));
+        assert_eq!(unsafe { cell.get_mut().take() }, Some(3));
+        assert_eq!(unsafe { *cell.get_mut() }, None);
+    }
+}
@@ -0,0 +1,182 @@
+use crate::{serial, serial_println, shell, time};
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+static LOGGER: Logger = Logger;
+static STATE: Mutex<State> = Mutex::new(State {
+    default: LevelFilter::Info,
+    targets: Vec::new(),
+    sinks: Vec::new(),
+});
+
+/// Where log lines go. Sinks are called with interrupts disabled, so they must be quick.
+pub trait Sink: Send {
+    fn write(&mut self, level: Level, line: fmt::Arguments);
+}
+
+/// Writes log lines to COM1.
+pub struct SerialSink;
+
+impl Sink for SerialSink {
+    fn write(&mut self, _level: Level, line: fmt::Arguments) {
+        serial_println!("{line}");
+    }
+}
+
+struct State {
+    /// Level of the targets without one of their own
+    default: LevelFilter,
+    /// Levels set for a target and the modules in it, like `kernel::apic`
+    targets: Vec<(String, LevelFilter)>,
+    sinks: Vec<(Box<dyn Sink>, LevelFilter)>,
+}
+
+impl State {
+    /// Level of the most specific target that `target` is in.
+    fn level(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .filter(|(prefix, _)| is_in(target, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// Lets the `log` macros skip records that no filter lets through.
+    fn update_max_level(&self) {
+        let max = self
+            .targets
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max);
+        log::set_max_level(max);
+    }
+}
+
+/// Whether `target` is `prefix` or one of its modules.
+fn is_in(target: &str, prefix: &str) -> bool {
+    match target.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        without_interrupts(|| metadata.level() <= STATE.lock().level(metadata.target()))
+    }
+
+    fn log(&self, record: &Record) {
+        let ticks = time::ticks();
+        without_interrupts(|| {
+            let mut state = STATE.lock();
+            if record.level() > state.level(record.target()) {
+                return;
+            }
+            for (sink, level) in state.sinks.iter_mut() {
+                if record.level() <= *level {
+                    sink.write(
+                        record.level(),
+                        format_args!(
+                            "[{ticks:>8}] {:<5} {}: {}",
+                            record.level(),
+                            record.target(),
+                            record.args()
+                        ),
+                    );
+                }
+            }
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the kernel logger for the `log` macros, writing to serial at `level` and above, and
+/// adds the `log` command to the shell. Records are stamped with the timer tick count.
+pub fn init(level: LevelFilter) {
+    without_interrupts(|| {
+        let mut state = STATE.lock();
+        state.default = level;
+        state.sinks.push((Box::new(SerialSink), LevelFilter::Trace));
+        state.update_max_level();
+    });
+    log::set_logger(&LOGGER).expect("logger::init called twice");
+    shell::command(
+        "log",
+        "[target] [level] shows or sets the log level, of a target or the default",
+        log_command,
+    );
+}
+
+/// Adds a sink receiving the records at `level` and above that the filters let through.
+pub fn add_sink(sink: impl Sink + 'static, level: LevelFilter) {
+    without_interrupts(|| STATE.lock().sinks.push((Box::new(sink), level)));
+}
+
+/// Sets the level of the targets without one of their own.
+pub fn set_level(level: LevelFilter) {
+    without_interrupts(|| {
+        let mut state = STATE.lock();
+        state.default = level;
+        state.update_max_level();
+    });
+}
+
+/// Sets the level of a target and of its modules, overriding the default. A target is a module
+/// path unless the record names another one.
+pub fn set_target_level(target: &str, level: LevelFilter) {
+    without_interrupts(|| {
+        let mut state = STATE.lock();
+        state.targets.retain(|(prefix, _)| prefix != target);
+        state.targets.push((target.to_string(), level));
+        state.update_max_level();
+    });
+}
+
+/// Removes the level of a target, which then gets the default or that of a parent module.
+pub fn clear_target_level(target: &str) {
+    without_interrupts(|| {
+        let mut state = STATE.lock();
+        state.targets.retain(|(prefix, _)| prefix != target);
+        state.update_max_level();
+    });
+}
+
+/// `log`, `log <level>`, `log <target> <level>` and `log <target> default`.
+fn log_command(args: &str) {
+    let mut args = args.split_whitespace();
+    match (args.next(), args.next()) {
+        (None, _) => {
+            let (default, targets) = without_interrupts(|| {
+                let state = STATE.lock();
+                (state.default, state.targets.clone())
+            });
+            serial::with_writer(|serial| {
+                let _ = writeln!(serial, "default: {default}");
+                for (target, level) in targets {
+                    let _ = writeln!(serial, "{target}: {level}");
+                }
+            });
+        }
+        (Some(level), None) => match level.parse() {
+            Ok(level) => set_level(level),
+            Err(_) => {
+                serial_println!("unknown level {level}, try off, error ... trace");
+            }
+        },
+        (Some(target), Some("default")) => clear_target_level(target),
+        (Some(target), Some(level)) => match level.parse() {
+            Ok(level) => set_target_level(target, level),
+            Err(_) => {
+                serial_println!("unknown level {level}, try off, error ... trace");
+            }
+        },
+    }
+}
@@ -0,0 +1,513 @@
+use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::{MapToError, TranslateResult};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Frames below 1 MiB are left alone, the same way the bootloader does, so they stay available
+/// for things that need conventional memory.
+const LOWER_MEMORY_END: u64 = 0x10_0000;
+
+/// Virtual range that `map_mmio` places device registers in
+const MMIO_START: u64 = 0x_5555_0000_0000;
+const MMIO_END: u64 = MMIO_START + 0x1_0000_0000;
+
+/// Virtual range of user programs. It is the one level 4 entry that each `AddressSpace` has of
+/// its own, all the others belong to the kernel and are the same in every address space.
+pub const USER_START: u64 = 0x_1000_0000_0000;
+pub const USER_END: u64 = USER_START + (1 << 39);
+const USER_P4_INDEX: usize = (USER_START >> 39) as usize;
+
+static MEMORY: Mutex<Option<Memory>> = Mutex::new(None);
+/// Set by `init`, so that `activate` can switch page tables without taking the lock
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+/// Level 4 table of the kernel, the one that was active when `init` was called
+static KERNEL_P4: AtomicU64 = AtomicU64::new(0);
+
+struct Memory {
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BootInfoFrameAllocator,
+    /// Next free address in the MMIO range
+    next_mmio: u64,
+}
+
+/// Frame allocator that hands out the `Usable` frames reported by the bootloader. Frames given
+/// back are kept in a list threaded through the frames themselves, and handed out first.
+pub struct BootInfoFrameAllocator {
+    memory_regions: &'static MemoryRegions,
+    region: usize,
+    next: u64,
+    physical_memory_offset: VirtAddr,
+    /// First frame given back, which holds the address of the next one, 0 ending the list
+    freed: u64,
+    freed_count: u64,
+}
+
+impl BootInfoFrameAllocator {
+    /// Creates a frame allocator over the given memory map.
+    ///
+    /// ## Safety
+    /// The memory map must be valid, the `Usable` frames in it must not be used by anything
+    /// else, and all physical memory must be mapped at `physical_memory_offset`.
+    pub unsafe fn init(
+        memory_regions: &'static MemoryRegions,
+        physical_memory_offset: VirtAddr,
+    ) -> Self {
+        BootInfoFrameAllocator {
+            memory_regions,
+            region: 0,
+            next: 0,
+            physical_memory_offset,
+            freed: 0,
+            freed_count: 0,
+        }
+    }
+
+    /// Returns the number of frames that have not been handed out yet.
+    pub fn free_frames(&self) -> u64 {
+        self.freed_count
+            + self
+                .memory_regions
+                .iter()
+                .enumerate()
+                .skip(self.region)
+                .filter(|(_, x)| x.kind == MemoryRegionKind::Usable)
+                .map(|(i, x)| {
+                    let start = if i == self.region {
+                        self.next.max(x.start)
+                    } else {
+                        x.start
+                    };
+                    let start = align_up(start.max(LOWER_MEMORY_END), 4096);
+                    x.end.saturating_sub(start) / 4096
+                })
+                .sum::<u64>()
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if self.freed != 0 {
+            let frame = PhysFrame::containing_address(PhysAddr::new(self.freed));
+            self.freed = unsafe { *(self.physical_memory_offset + self.freed).as_ptr::<u64>() };
+            self.freed_count -= 1;
+            return Some(frame);
+        }
+        while let Some(region) = self.memory_regions.get(self.region) {
+            if region.kind == MemoryRegionKind::Usable {
+                let start = align_up(self.next.max(region.start).max(LOWER_MEMORY_END), 4096);
+                if start + 4096 <= region.end {
+                    self.next = start + 4096;
+                    return Some(PhysFrame::containing_address(PhysAddr::new(start)));
+                }
+            }
+            self.region += 1;
+        }
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let addr = frame.start_address().as_u64();
+        *(self.physical_memory_offset + addr).as_mut_ptr::<u64>() = self.freed;
+        self.freed = addr;
+        self.freed_count += 1;
+    }
+}
+
+/// Returns the active level 4 page table.
+///
+/// ## Safety
+/// All physical memory must be mapped at `physical_memory_offset`, and the returned reference
+/// must be the only one to the table.
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    let (level_4_table_frame, _) = Cr3::read();
+    let virt = physical_memory_offset + level_4_table_frame.start_address().as_u64();
+    &mut *virt.as_mut_ptr()
+}
+
+/// Sets up the page-table mapper and the frame allocator used by the other functions of this
+/// module.
+///
+/// ## Safety
+/// Must be called only once. All physical memory must be mapped at `physical_memory_offset`,
+/// and the `Usable` regions of the memory map must not be used by anything else.
+pub unsafe fn init(physical_memory_offset: u64, memory_regions: &'static MemoryRegions) {
+    let physical_memory_offset = VirtAddr::new(physical_memory_offset);
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    KERNEL_P4.store(Cr3::read().0.start_address().as_u64(), Ordering::Relaxed);
+    *MEMORY.lock() = Some(Memory {
+        mapper: OffsetPageTable::new(level_4_table, physical_memory_offset),
+        frame_allocator: BootInfoFrameAllocator::init(memory_regions, physical_memory_offset),
+        next_mmio: MMIO_START,
+    });
+}
+
+/// Runs `f` with the mapper and the frame allocator, with interrupts disabled. The mapper works
+/// on the kernel's page table, whose new entries reach an active user address space before
+/// this returns. Panics if `init` has not been called.
+pub fn with_mapper<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator) -> R,
+) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut memory = MEMORY.lock();
+        let memory = memory.as_mut().expect("memory::init has not been called");
+        let result = f(&mut memory.mapper, &mut memory.frame_allocator);
+        let active = Cr3::read().0;
+        if active.start_address().as_u64() != KERNEL_P4.load(Ordering::Relaxed) {
+            sync_kernel_entries(unsafe { table(memory.mapper.phys_offset(), active) });
+        }
+        result
+    })
+}
+
+/// Maps the pages covering `start..start + size` to freshly allocated frames.
+pub fn map_pages(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    with_mapper(|mapper, frame_allocator| {
+        for page in pages(start, size) {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            unsafe {
+                mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Maps the pages covering `virt..virt + size` to the physical range starting at `phys`, for
+/// example to reach MMIO registers. The mapping is uncached.
+///
+/// ## Safety
+/// The physical range must not be in use as ordinary memory by anything else.
+pub unsafe fn map_physical(
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    with_mapper(|mapper, frame_allocator| {
+        let first_page = Page::<Size4KiB>::containing_address(virt);
+        for page in pages(virt, size) {
+            let offset = page.start_address() - first_page.start_address();
+            let frame = PhysFrame::containing_address(phys + offset);
+            mapper
+                .map_to(
+                    page,
+                    frame,
+                    flags | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+                    frame_allocator,
+                )?
+                .flush();
+        }
+        Ok(())
+    })
+}
+
+/// Maps `size` bytes of device registers starting at `phys` into the MMIO range, uncached, and
+/// returns the virtual address of `phys`.
+///
+/// ## Safety
+/// The physical range must belong to a device, not to ordinary memory.
+pub unsafe fn map_mmio(phys: PhysAddr, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let offset = phys.as_u64() & 0xfff;
+    let size = align_up(offset + size, 4096);
+    let start = x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut memory = MEMORY.lock();
+        let memory = memory.as_mut().expect("memory::init has not been called");
+        let start = memory.next_mmio;
+        assert!(start + size <= MMIO_END, "MMIO range exhausted");
+        // leave an unmapped page between mappings
+        memory.next_mmio += size + 4096;
+        start
+    });
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    map_physical(VirtAddr::new(start), phys.align_down(4096u64), size, flags)?;
+    Ok(VirtAddr::new(start + offset))
+}
+
+/// Returns the address at which the given physical address can be read through the bootloader's
+/// mapping of all physical memory.
+pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+    with_mapper(|mapper, _| mapper.phys_offset() + phys.as_u64())
+}
+
+/// Translates a virtual address through the active page table.
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    with_active_mapper(|mapper| match mapper.translate(addr) {
+        TranslateResult::Mapped { frame, offset, .. } => Some(frame.start_address() + offset),
+        _ => None,
+    })
+}
+
+/// Whether every page covering `start..start + size` is mapped in the active page table and
+/// accessible from ring 3.
+pub fn is_user_accessible(start: VirtAddr, size: u64) -> bool {
+    with_active_mapper(|mapper| {
+        pages(start, size).all(|page| match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => {
+                flags.contains(PageTableFlags::USER_ACCESSIBLE)
+            }
+            _ => false,
+        })
+    })
+}
+
+/// Runs `f` with a mapper over the active page table, which is the kernel's or that of the user
+/// program running, with interrupts disabled.
+fn with_active_mapper<R>(f: impl FnOnce(&OffsetPageTable) -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed));
+        f(&unsafe { OffsetPageTable::new(active_level_4_table(offset), offset) })
+    })
+}
+
+/// Page tables of a user program. The entries outside `USER_START..USER_END` are the kernel's,
+/// and the user range is the program's own. Dropping it frees the page tables and every frame
+/// mapped in the user range.
+pub struct AddressSpace {
+    p4: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Creates an address space with nothing mapped in the user range.
+    pub fn new() -> Result<Self, &'static str> {
+        with_mapper(|mapper, frame_allocator| {
+            let p4 = frame_allocator
+                .allocate_frame()
+                .ok_or("out of physical memory")?;
+            let table = unsafe { table(mapper.phys_offset(), p4) };
+            table.zero();
+            sync_kernel_entries(table);
+            Ok(AddressSpace { p4 })
+        })
+    }
+
+    /// Frame of the level 4 table, for `activate`
+    pub fn p4(&self) -> PhysFrame {
+        self.p4
+    }
+
+    /// Maps the pages covering `start..start + size` in the user range to zeroed frames,
+    /// writable and accessible from ring 3.
+    pub fn map_user(&mut self, start: VirtAddr, size: u64) -> Result<(), &'static str> {
+        if start.as_u64() < USER_START || USER_END - start.as_u64() < size {
+            return Err("address out of the user range");
+        }
+        let flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        with_mapper(|kernel_mapper, frame_allocator| {
+            let offset = kernel_mapper.phys_offset();
+            let mut mapper = unsafe { OffsetPageTable::new(table(offset, self.p4), offset) };
+            for page in pages(start, size) {
+                let frame = frame_allocator
+                    .allocate_frame()
+                    .ok_or("out of physical memory")?;
+                // frames come back with whatever their last user left in them
+                unsafe { table(offset, frame) }.zero();
+                match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                    // the table is not active, nothing to flush
+                    Ok(flush) => flush.ignore(),
+                    Err(_) => {
+                        unsafe { frame_allocator.deallocate_frame(frame) };
+                        return Err("cannot map user memory");
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Copies `bytes` to `start`, which must be mapped with `map_user`.
+    pub fn write(&mut self, start: VirtAddr, bytes: &[u8]) -> Result<(), &'static str> {
+        self.copy(start, bytes.len(), |phys, range| {
+            let piece = &bytes[range];
+            unsafe { core::ptr::copy_nonoverlapping(piece.as_ptr(), phys, piece.len()) };
+        })
+    }
+
+    /// Reads `buffer.len()` bytes at `start`, which must be mapped with `map_user`.
+    pub fn read(&self, start: VirtAddr, buffer: &mut [u8]) -> Result<(), &'static str> {
+        self.copy(start, buffer.len(), |phys, range| {
+            let piece = &mut buffer[range];
+            unsafe { core::ptr::copy_nonoverlapping(phys, piece.as_mut_ptr(), piece.len()) };
+        })
+    }
+
+    /// Calls `f` with the address, through the mapping of all physical memory, of each page-sized
+    /// piece of `start..start + len`, and the range of the piece relative to `start`.
+    fn copy(
+        &self,
+        start: VirtAddr,
+        len: usize,
+        mut f: impl FnMut(*mut u8, core::ops::Range<usize>),
+    ) -> Result<(), &'static str> {
+        with_mapper(|kernel_mapper, _| {
+            let offset = kernel_mapper.phys_offset();
+            let mapper = unsafe { OffsetPageTable::new(table(offset, self.p4), offset) };
+            let mut done = 0;
+            while done < len {
+                let addr = start + done as u64;
+                let piece = (4096 - addr.as_u64() as usize % 4096).min(len - done);
+                let TranslateResult::Mapped {
+                    frame,
+                    offset: page_offset,
+                    flags,
+                } = mapper.translate(addr)
+                else {
+                    return Err("address not mapped");
+                };
+                if !flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+                    return Err("address not mapped");
+                }
+                let phys = frame.start_address() + page_offset;
+                f((offset + phys.as_u64()).as_mut_ptr(), done..done + piece);
+                done += piece;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Must not be the active page table any more, see `activate`.
+    fn drop(&mut self) {
+        with_mapper(|mapper, frame_allocator| unsafe {
+            let offset = mapper.phys_offset();
+            let p4 = table(offset, self.p4);
+            free_table(offset, &p4[USER_P4_INDEX], 3, frame_allocator);
+            frame_allocator.deallocate_frame(self.p4);
+        });
+    }
+}
+
+/// Frees the table an entry at `level` points to, the tables below it and the frames they map.
+///
+/// ## Safety
+/// Nothing may use the table or the frames afterwards.
+unsafe fn free_table(
+    offset: VirtAddr,
+    entry: &x86_64::structures::paging::page_table::PageTableEntry,
+    level: u8,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) {
+    // the user range only holds 4 KiB pages
+    let Ok(frame) = entry.frame() else {
+        return;
+    };
+    if level > 0 {
+        for entry in table(offset, frame).iter() {
+            free_table(offset, entry, level - 1, frame_allocator);
+        }
+    }
+    frame_allocator.deallocate_frame(frame);
+}
+
+/// Makes the level 4 table in `p4` the active page table, or the kernel's with `None`.
+/// A user table first gets the kernel's entries again, in case the kernel has used more of
+/// its ranges since the last time.
+pub(crate) fn activate(p4: Option<PhysFrame>) {
+    let kernel = PhysFrame::containing_address(PhysAddr::new(KERNEL_P4.load(Ordering::Relaxed)));
+    let frame = match p4 {
+        Some(p4) => {
+            let offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed));
+            sync_kernel_entries(unsafe { table(offset, p4) });
+            p4
+        }
+        None => kernel,
+    };
+    let (active, flags) = Cr3::read();
+    if active != frame {
+        unsafe { Cr3::write(frame, flags) };
+    }
+}
+
+/// Copies the kernel's level 4 entries, all but the user range, into `table`.
+fn sync_kernel_entries(table: &mut PageTable) {
+    let offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed));
+    let kernel = PhysFrame::containing_address(PhysAddr::new(KERNEL_P4.load(Ordering::Relaxed)));
+    let kernel = unsafe { self::table(offset, kernel) };
+    for (index, entry) in kernel.iter().enumerate() {
+        if index != USER_P4_INDEX {
+            table[index] = entry.clone();
+        }
+    }
+}
+
+/// Returns the page table in `frame`.
+///
+/// ## Safety
+/// All physical memory must be mapped at `offset`, and the returned reference must be the only
+/// one to the table.
+unsafe fn table(offset: VirtAddr, frame: PhysFrame) -> &'static mut PageTable {
+    &mut *(offset + frame.start_address().as_u64()).as_mut_ptr()
+}
+
+/// Returns the number of physical frames that can still be mapped.
+pub fn free_frames() -> u64 {
+    with_mapper(|_, frame_allocator| frame_allocator.free_frames())
+}
+
+fn pages(start: VirtAddr, size: u64) -> impl Iterator<Item = Page<Size4KiB>> {
+    let first = Page::containing_address(start);
+    let last = Page::containing_address(start + size.max(1) - 1u64);
+    Page::range_inclusive(first, last)
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn address_space_round_trips_bytes_across_pages() {
+        let mut space = AddressSpace::new().unwrap();
+        let start = VirtAddr::new(USER_START + 4096 - 3);
+        space.map_user(start, 6).unwrap();
+        space.write(start, b"abcdef").unwrap();
+        let mut bytes = [0; 6];
+        space.read(start, &mut bytes).unwrap();
+        assert_eq!(&bytes, b"abcdef");
+    }
+
+    #[test_case]
+    fn address_space_stays_out_of_the_kernel() {
+        let mut space = AddressSpace::new().unwrap();
+        assert!(space
+            .map_user(VirtAddr::new(USER_START - 4096), 4096)
+            .is_err());
+        assert!(space
+            .map_user(VirtAddr::new(USER_END - 4096), 8192)
+            .is_err());
+        assert!(space.read(VirtAddr::new(USER_START), &mut [0]).is_err());
+    }
+
+    #[test_case]
+    fn dropping_an_address_space_frees_its_frames() {
+        let free = with_mapper(|_, frame_allocator| frame_allocator.free_frames());
+        let mut space = AddressSpace::new().unwrap();
+        space
+            .map_user(VirtAddr::new(USER_START), 64 * 1024)
+            .unwrap();
+        drop(space);
+        assert_eq!(
+            with_mapper(|_, frame_allocator| frame_allocator.free_frames()),
+            free
+        );
+    }
+}
@@ -0,0 +1,192 @@
+use crate::memory::{AddressSpace, USER_START};
+use crate::thread::{self, JoinHandle, ThreadId};
+use crate::{elf, gdt, ramdisk, syscall};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use core::arch::naked_asm;
+use spin::Mutex;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+use x86_64::VirtAddr;
+
+/// Size of the range a program gets in its own address space, from `memory::USER_START`
+const USER_SIZE: u64 = 0x4000_0000;
+/// Stack size of user programs, at the top of their range
+const USER_STACK_SIZE: u64 = 64 * 1024;
+/// Stack interrupts and syscalls from ring 3 run on
+const INTERRUPT_STACK_SIZE: usize = 16 * 1024;
+
+/// Exit code of a program the kernel stopped because it caused an exception
+pub const KILLED: u64 = u64::MAX;
+
+/// Ring 3 state of the threads running user code, by thread
+static RUNNING: Mutex<BTreeMap<ThreadId, usize>> = Mutex::new(BTreeMap::new());
+
+/// What a thread needs to get back into the kernel when its program ends.
+struct UserContext {
+    /// Kernel stack pointer saved by `enter_user`, which `leave_user` returns to
+    kernel_rsp: u64,
+    /// Stack for interrupts and syscalls arriving in ring 3. It is separate from the thread's
+    /// own stack so that it cannot overwrite the frames `leave_user` returns to.
+    interrupt_stack: Box<[u8]>,
+}
+
+/// Creates the address space of a program, with a stack mapped at the top of its range and an
+/// unmapped guard page below. Returns it with the top of the stack.
+fn new_address_space() -> Result<(AddressSpace, VirtAddr), &'static str> {
+    let mut space = AddressSpace::new()?;
+    let stack_top = VirtAddr::new(USER_START + USER_SIZE);
+    space.map_user(stack_top - USER_STACK_SIZE, USER_STACK_SIZE)?;
+    Ok((space, stack_top))
+}
+
+/// Starts a thread running a flat binary in ring 3, in an address space of its own. The image
+/// is loaded at `memory::USER_START` and entered at its first byte. The thread finishes with
+/// the program's exit code, and the program's memory is freed.
+pub fn spawn(image: &[u8]) -> Result<JoinHandle<u64>, &'static str> {
+    if image.len() as u64 > USER_SIZE - USER_STACK_SIZE - 4096 {
+        return Err("program too large");
+    }
+    let (mut space, stack_top) = new_address_space()?;
+    let start = VirtAddr::new(USER_START);
+    space.map_user(start, image.len() as u64)?;
+    space.write(start, image)?;
+    Ok(thread::spawn(move || run(space, start, stack_top)))
+}
+
+/// Starts a thread running a position-independent ELF executable in ring 3, in an address
+/// space of its own where it is loaded at `memory::USER_START`. The thread finishes with the
+/// program's exit code, and the program's memory is freed, as it is if the program fails to
+/// load.
+pub fn spawn_elf(image: &[u8]) -> Result<JoinHandle<u64>, &'static str> {
+    let (mut space, stack_top) = new_address_space()?;
+    // keep the guard page below the stack unmapped
+    let size = USER_SIZE - USER_STACK_SIZE - 4096;
+    let entry = elf::load(image, &mut space, VirtAddr::new(USER_START), size)?;
+    Ok(thread::spawn(move || run(space, entry, stack_top)))
+}
+
+/// Starts the program called `name` from the ramdisk, see `spawn_elf`.
+pub fn spawn_program(name: &str) -> Result<JoinHandle<u64>, &'static str> {
+    spawn_elf(ramdisk::find(name).ok_or("no such program in the ramdisk")?)
+}
+
+/// Runs user code on the current thread, in ring 3 and in `space`, until it exits. Returns its
+/// exit code.
+pub(crate) fn run(space: AddressSpace, entry: VirtAddr, stack_top: VirtAddr) -> u64 {
+    let mut context = Box::new(UserContext {
+        kernel_rsp: 0,
+        interrupt_stack: vec![0; INTERRUPT_STACK_SIZE].into_boxed_slice(),
+    });
+    let interrupt_stack_top = context.interrupt_stack.as_ptr() as u64 + INTERRUPT_STACK_SIZE as u64;
+    let id = thread::current();
+    without_interrupts(|| {
+        RUNNING
+            .lock()
+            .insert(id, &mut *context as *mut UserContext as usize)
+    });
+    syscall::focus(id);
+    thread::set_kernel_stack(Some(interrupt_stack_top & !0xf));
+    thread::set_address_space(Some(space.p4()));
+
+    let (code_selector, stack_selector) = gdt::user_selectors();
+    let exit_code = unsafe {
+        enter_user(
+            entry.as_u64(),
+            // as if the entry point had been called
+            stack_top.as_u64() - 8,
+            &mut context.kernel_rsp,
+            code_selector.0 as u64,
+            stack_selector.0 as u64,
+        )
+    };
+    // `leave_user` comes back here from a syscall or an exception, with interrupts disabled
+    thread::set_kernel_stack(None);
+    thread::set_address_space(None);
+    without_interrupts(|| RUNNING.lock().remove(&id));
+    syscall::unfocus(id);
+    interrupts::enable();
+    // no longer active, so its memory can go
+    drop(space);
+    exit_code
+}
+
+/// Ends the user program of the current thread, making its `run` return `code`.
+/// Must be called from a syscall or an exception that came from ring 3.
+pub(crate) fn exit(code: u64) -> ! {
+    let context = without_interrupts(|| RUNNING.lock().get(&thread::current()).copied())
+        .expect("exit called by a thread without a user program");
+    let kernel_rsp = unsafe { (*(context as *const UserContext)).kernel_rsp };
+    unsafe { leave_user(kernel_rsp, code) }
+}
+
+/// Stops the user program that caused an exception. Returns if the exception did not come
+/// from ring 3, so the kernel can treat it as its own.
+pub(crate) fn kill_on_exception(name: &str, code_segment: u64) {
+    if code_segment & 3 != 3 {
+        return;
+    }
+    log::warn!("user program killed by exception: {name}");
+    exit(KILLED);
+}
+
+/// Saves the callee-saved registers and the stack pointer into `*kernel_rsp`, then drops to
+/// ring 3 at `entry` with the stack pointer at `user_stack`. Returns when `leave_user` is
+/// called.
+#[unsafe(naked)]
+unsafe extern "C" fn enter_user(
+    entry: u64,
+    user_stack: u64,
+    kernel_rsp: *mut u64,
+    code_selector: u64,
+    stack_selector: u64,
+) -> u64 {
+    naked_asm!(
+        "push rbx",
+        "push rbp",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rdx], rsp",
+        // interrupt frame for iretq, with interrupts enabled
+        "push r8",
+        "push rsi",
+        "push 0x202",
+        "push rcx",
+        "push rdi",
+        // do not leak kernel values to the program
+        "xor eax, eax",
+        "xor ebx, ebx",
+        "xor ecx, ecx",
+        "xor edx, edx",
+        "xor esi, esi",
+        "xor edi, edi",
+        "xor ebp, ebp",
+        "xor r8d, r8d",
+        "xor r9d, r9d",
+        "xor r10d, r10d",
+        "xor r11d, r11d",
+        "xor r12d, r12d",
+        "xor r13d, r13d",
+        "xor r14d, r14d",
+        "xor r15d, r15d",
+        "iretq",
+    )
+}
+
+/// Returns from `enter_user` with `exit_code`, on the stack it saved.
+#[unsafe(naked)]
+unsafe extern "C" fn leave_user(kernel_rsp: u64, exit_code: u64) -> ! {
+    naked_asm!(
+        "mov rsp, rdi",
+        "mov rax, rsi",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbp",
+        "pop rbx",
+        "ret",
+    )
+}
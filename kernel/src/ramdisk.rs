@@ -0,0 +1,78 @@
+use core::str;
+use spin::Mutex;
+
+/// First bytes of a ramdisk made by `build.rs`. It is followed by the programs, each as a
+/// little-endian u32 name length, a little-endian u64 image length, the UTF-8 name and the image.
+pub const MAGIC: &[u8; 8] = b"LABOSRD1";
+
+static RAMDISK: Mutex<&'static [u8]> = Mutex::new(&[]);
+
+/// A program bundled in the ramdisk.
+#[derive(Debug, Clone, Copy)]
+pub struct Program {
+    pub name: &'static str,
+    /// ELF executable, see `process::spawn_elf`
+    pub image: &'static [u8],
+}
+
+/// Iterator over the programs of the ramdisk, see `programs`.
+pub struct Programs {
+    rest: &'static [u8],
+}
+
+impl Iterator for Programs {
+    type Item = Program;
+
+    fn next(&mut self) -> Option<Program> {
+        let (program, rest) = parse_program(self.rest).ok()??;
+        self.rest = rest;
+        Some(program)
+    }
+}
+
+/// Checks the ramdisk the bootloader loaded and makes its programs available.
+pub(crate) fn init(ramdisk: &'static [u8]) -> Result<(), &'static str> {
+    let mut rest = ramdisk
+        .strip_prefix(MAGIC)
+        .ok_or("not a ramdisk made by build.rs")?;
+    while let Some((_, next)) = parse_program(rest)? {
+        rest = next;
+    }
+    *RAMDISK.lock() = ramdisk;
+    Ok(())
+}
+
+/// Reads the program at the start of `bytes`, returning it and what follows. Returns `None` at
+/// the end of the ramdisk.
+fn parse_program(bytes: &'static [u8]) -> Result<Option<(Program, &'static [u8])>, &'static str> {
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+    let (name_len, rest) = bytes.split_first_chunk::<4>().ok_or("truncated ramdisk")?;
+    let (image_len, rest) = rest.split_first_chunk::<8>().ok_or("truncated ramdisk")?;
+    let name_len = u32::from_le_bytes(*name_len) as usize;
+    let image_len = usize::try_from(u64::from_le_bytes(*image_len)).or(Err("truncated ramdisk"))?;
+    if rest.len() < name_len || rest.len() - name_len < image_len {
+        return Err("truncated ramdisk");
+    }
+    let (name, rest) = rest.split_at(name_len);
+    let (image, rest) = rest.split_at(image_len);
+    let name = str::from_utf8(name).or(Err("program name is not UTF-8"))?;
+    Ok(Some((Program { name, image }, rest)))
+}
+
+/// The programs in the ramdisk, in the order `build.rs` added them. Empty if there is no
+/// ramdisk.
+pub fn programs() -> Programs {
+    let ramdisk = *RAMDISK.lock();
+    Programs {
+        rest: ramdisk.get(MAGIC.len()..).unwrap_or(&[]),
+    }
+}
+
+/// Returns the image of the program called `name`.
+pub fn find(name: &str) -> Option<&'static [u8]> {
+    programs()
+        .find(|program| program.name == name)
+        .map(|program| program.image)
+}
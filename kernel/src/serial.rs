@@ -0,0 +1,91 @@
+use core::fmt::{self, Write};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use uart_16550::SerialPort;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// I/O port of COM1
+const COM1: u16 = 0x3F8;
+
+lazy_static! {
+    /// COM1, initialised on first use. Initialising it again would throw away the bytes it has
+    /// received.
+    static ref SERIAL1: Mutex<SerialPort> = {
+        let mut port = unsafe { SerialPort::new(COM1) };
+        port.init();
+        Mutex::new(port)
+    };
+}
+
+/// Runs `f` on COM1, locked and with interrupts disabled so that an interrupt handler using the
+/// port meanwhile cannot deadlock. Output written within one call is not interleaved with other
+/// output.
+pub fn with_port<R>(f: impl FnOnce(&mut SerialPort) -> R) -> R {
+    without_interrupts(|| f(&mut SERIAL1.lock()))
+}
+
+/// Runs `f` on COM1 as a text writer, locked like `with_port`.
+pub fn with_writer<R>(f: impl FnOnce(&mut Writer) -> R) -> R {
+    with_port(|port| f(&mut Writer(port)))
+}
+
+/// Runs `f` on COM1 as a text writer without waiting for the lock, for handlers that may have
+/// interrupted its holder, like an NMI or a breakpoint inside `with_port`. If the lock is taken,
+/// `f` writes to the port directly and its output may land in the middle of the holder's.
+pub fn with_writer_nowait<R>(f: impl FnOnce(&mut Writer) -> R) -> R {
+    without_interrupts(|| match SERIAL1.try_lock() {
+        Some(mut port) => f(&mut Writer(&mut port)),
+        None => f(&mut Writer(&mut unsafe { SerialPort::new(COM1) })),
+    })
+}
+
+/// Text output on COM1. Lines end with `\r\n` whatever the text uses, so that the output does not
+/// staircase on a raw terminal.
+pub struct Writer<'a>(&'a mut SerialPort);
+
+impl Write for Writer<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (index, line) in s.split('\n').enumerate() {
+            if index > 0 {
+                self.0.write_str("\r\n")?;
+            }
+            self.0.write_str(line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Releases the lock on COM1, whoever holds it, so that a panic or a fatal exception can report
+/// itself even if it interrupted a print.
+///
+/// ## Safety
+/// The code holding the lock must never run again, or its output may be mixed with other output.
+pub unsafe fn force_unlock() {
+    SERIAL1.force_unlock();
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    with_writer(|writer| {
+        let _ = writer.write_fmt(args);
+    });
+}
+
+/// Prints to COM1, like `print!`.
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => {
+        $crate::serial::_print(format_args!($($arg)*))
+    };
+}
+
+/// Prints to COM1 with a newline, like `println!`.
+#[macro_export]
+macro_rules! serial_println {
+    () => {
+        $crate::serial_print!("\n")
+    };
+    ($($arg:tt)*) => {
+        $crate::serial::_print(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
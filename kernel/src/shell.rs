@@ -0,0 +1,170 @@
+use crate::{handlers, serial, serial_print, serial_println};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use spin::Mutex;
+
+type CommandHandler = Box<dyn FnMut(&str) + Send>;
+
+const PROMPT: &str = "> ";
+/// Longest line kept, further characters are ignored
+const MAX_LINE: usize = 256;
+
+static SHELL: Mutex<Shell> = Mutex::new(Shell {
+    line: String::new(),
+    previous: String::new(),
+    escape: Escape::None,
+    last_byte: 0,
+    commands: Vec::new(),
+});
+
+/// Line editor and command table of the debug shell on COM1.
+struct Shell {
+    line: String,
+    /// Last line run, recalled with the up arrow
+    previous: String,
+    escape: Escape,
+    last_byte: u8,
+    commands: Vec<Command>,
+}
+
+/// Where the shell is in an ANSI escape sequence, like the `ESC [ A` of the up arrow.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    Started,
+    Csi,
+}
+
+/// A command. Its closure is taken out while it runs, so that it can add commands itself.
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    run: Option<CommandHandler>,
+}
+
+/// Adds a command to the shell. `run` gets the rest of the line after the name, trimmed, and
+/// prints its output with `serial_println!`. A command with the name of an existing one
+/// replaces it.
+pub fn command(name: &'static str, help: &'static str, run: impl FnMut(&str) + Send + 'static) {
+    let mut shell = SHELL.lock();
+    shell.commands.retain(|command| command.name != name);
+    shell.commands.push(Command {
+        name,
+        help,
+        run: Some(Box::new(run)),
+    });
+}
+
+/// Starts reading commands from COM1.
+pub(crate) fn init() {
+    handlers::on_serial(input);
+    serial_print!("\nDebug shell ready, type help for the commands\n{PROMPT}");
+}
+
+/// Edits the line with a received byte, and runs it at the end of the line.
+fn input(byte: u8) {
+    let mut shell = SHELL.lock();
+    let last_byte = core::mem::replace(&mut shell.last_byte, byte);
+    match (shell.escape, byte) {
+        (Escape::Started, b'[') => shell.escape = Escape::Csi,
+        (Escape::Csi, b'A') => {
+            shell.escape = Escape::None;
+            erase(&mut shell.line);
+            shell.line = shell.previous.clone();
+            serial_print!("{}", shell.line);
+        }
+        // parameters of a sequence
+        (Escape::Csi, 0x20..=0x3f) => {}
+        (Escape::Started | Escape::Csi, _) => shell.escape = Escape::None,
+        (Escape::None, 0x1b) => shell.escape = Escape::Started,
+        // the second half of a CR LF line ending
+        (Escape::None, b'\n') if last_byte == b'\r' => {}
+        (Escape::None, b'\r' | b'\n') => {
+            serial_println!();
+            let line = core::mem::take(&mut shell.line);
+            if !line.trim().is_empty() {
+                shell.previous = line.clone();
+            }
+            drop(shell);
+            run(line.trim());
+            serial_print!("{PROMPT}");
+        }
+        // backspace and delete
+        (Escape::None, 0x08 | 0x7f) => {
+            if shell.line.pop().is_some() {
+                serial::with_port(|port| port.send(0x08));
+            }
+        }
+        // ctrl-c
+        (Escape::None, 0x03) => {
+            shell.line.clear();
+            serial_print!("^C\n{PROMPT}");
+        }
+        // ctrl-u
+        (Escape::None, 0x15) => erase(&mut shell.line),
+        (Escape::None, 0x20..=0x7e) if shell.line.len() < MAX_LINE => {
+            shell.line.push(byte as char);
+            serial::with_port(|port| port.send(byte));
+        }
+        _ => {}
+    }
+}
+
+/// Clears the line, on the terminal too.
+fn erase(line: &mut String) {
+    serial::with_port(|port| {
+        for _ in line.chars() {
+            port.send(0x08);
+        }
+    });
+    line.clear();
+}
+
+/// Runs a command line.
+fn run(line: &str) {
+    let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+    if name.is_empty() {
+        return;
+    }
+    if name == "help" {
+        let shell = SHELL.lock();
+        serial::with_writer(|serial| {
+            let _ = writeln!(serial, "  {:<14}lists the commands", "help");
+            for command in shell.commands.iter() {
+                let _ = writeln!(serial, "  {:<14}{}", command.name, command.help);
+            }
+        });
+        return;
+    }
+    let run = {
+        let mut shell = SHELL.lock();
+        match shell
+            .commands
+            .iter_mut()
+            .find(|command| command.name == name)
+        {
+            Some(command) => command.run.take(),
+            None => {
+                serial_println!("unknown command {name}, try help");
+                return;
+            }
+        }
+    };
+    // a command that is already running, because it ran the shell itself, is skipped
+    let Some(mut run) = run else {
+        return;
+    };
+    run(args.trim());
+    let mut shell = SHELL.lock();
+    if let Some(command) = shell
+        .commands
+        .iter_mut()
+        .find(|command| command.name == name)
+    {
+        if command.run.is_none() {
+            command.run = Some(run);
+        }
+    }
+}
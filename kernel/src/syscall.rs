@@ -0,0 +1,209 @@
+use crate::thread::{self, context_switch, SavedRegisters, ThreadId};
+use crate::{handlers, memory, process, serial_print, telemetry, time};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use pc_keyboard::DecodedKey;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::VirtAddr;
+
+/// Interrupt vector of the syscall instruction, `int 0x80`
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+// Syscall numbers and results, shared with the programs
+pub use userlib::{
+    DRAW_RECT, ERROR, EXIT, NOW_NS, NO_KEY, NO_MESSAGE, RAW_KEY, READ_KEY, READ_MESSAGE,
+    RECORD_TELEMETRY, SLEEP, TICKS, WRITE_CONSOLE, WRITE_TEXT, YIELD,
+};
+
+/// Longest text `WRITE_TEXT`, `WRITE_CONSOLE` and `RECORD_TELEMETRY` accept, in bytes
+const MAX_TEXT: u64 = 4096;
+/// Keys kept for `READ_KEY` before new ones are dropped
+const KEY_CAPACITY: usize = 64;
+/// Messages kept for `READ_MESSAGE` before new ones are dropped
+const MESSAGE_CAPACITY: usize = 16;
+
+static SCREEN: Mutex<Option<Box<dyn Screen>>> = Mutex::new(None);
+static KEYS: Mutex<VecDeque<DecodedKey>> = Mutex::new(VecDeque::new());
+/// Threads running user programs, in the order they started. The last one gets the keys.
+static FOCUS: Mutex<Vec<ThreadId>> = Mutex::new(Vec::new());
+static MESSAGES: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// Drawing done on behalf of user programs. The kernel binary owns the framebuffer, so it
+/// provides this through `HandlerTable::screen`.
+pub trait Screen: Send {
+    fn draw_rect(&mut self, x: usize, y: usize, width: usize, height: usize, rgb: [u8; 3]);
+    /// Fails if `x`, `y` is off the screen.
+    fn write_text(&mut self, x: usize, y: usize, text: &str) -> Result<(), &'static str>;
+}
+
+/// Installs the screen and starts collecting keys for `READ_KEY`.
+pub(crate) fn init(screen: Option<Box<dyn Screen>>) {
+    without_interrupts(|| *SCREEN.lock() = screen);
+    handlers::on_keyboard(|key| {
+        without_interrupts(|| {
+            let mut keys = KEYS.lock();
+            if keys.len() < KEY_CAPACITY {
+                keys.push_back(key);
+            }
+        })
+    });
+}
+
+/// Gives the keys to the program starting on thread `id`, until it ends or another one starts.
+/// The keys typed so far are forgotten, so that it does not see them.
+pub(crate) fn focus(id: ThreadId) {
+    without_interrupts(|| {
+        FOCUS.lock().push(id);
+        KEYS.lock().clear();
+    });
+}
+
+/// Gives the keys back to the program that had them before the one on thread `id` started.
+pub(crate) fn unfocus(id: ThreadId) {
+    without_interrupts(|| {
+        FOCUS.lock().retain(|&focused| focused != id);
+        KEYS.lock().clear();
+    });
+}
+
+/// Queues a message for user programs, which the first to call `READ_MESSAGE` gets. The
+/// kernel binary forwards debug shell commands to the game this way. Returns false if the
+/// queue is full and the message was dropped.
+pub fn send_message(text: &str) -> bool {
+    without_interrupts(|| {
+        let mut messages = MESSAGES.lock();
+        if messages.len() == MESSAGE_CAPACITY {
+            return false;
+        }
+        messages.push_back(String::from(text));
+        true
+    })
+}
+
+/// Entry of `int 0x80`.
+#[unsafe(naked)]
+pub(crate) extern "C" fn syscall_entry() {
+    context_switch!(syscall_interrupt)
+}
+
+extern "C" fn syscall_interrupt(rsp: u64) -> u64 {
+    let registers = unsafe { &mut *(rsp as *mut SavedRegisters) };
+    let args = [
+        registers.rdi,
+        registers.rsi,
+        registers.rdx,
+        registers.r10,
+        registers.r8,
+    ];
+    registers.rax = match registers.rax {
+        EXIT => process::exit(args[0]),
+        YIELD => {
+            registers.rax = 0;
+            return thread::switch(rsp);
+        }
+        TICKS => time::ticks(),
+        READ_KEY => read_key(),
+        DRAW_RECT => draw_rect(args),
+        WRITE_TEXT => write_text(args),
+        SLEEP => {
+            thread::sleep(args[0]);
+            0
+        }
+        NOW_NS => time::now_ns(),
+        WRITE_CONSOLE => write_console(args),
+        RECORD_TELEMETRY => record_telemetry(args),
+        READ_MESSAGE => read_message(args),
+        _ => ERROR,
+    };
+    rsp
+}
+
+fn read_key() -> u64 {
+    if FOCUS.lock().last() != Some(&thread::current()) {
+        return NO_KEY;
+    }
+    match KEYS.lock().pop_front() {
+        Some(DecodedKey::Unicode(c)) => c as u64,
+        Some(DecodedKey::RawKey(code)) => RAW_KEY | code as u64,
+        None => NO_KEY,
+    }
+}
+
+fn draw_rect([x, y, width, height, rgb]: [u64; 5]) -> u64 {
+    let rgb = [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8];
+    match SCREEN.lock().as_mut() {
+        Some(screen) => {
+            screen.draw_rect(x as usize, y as usize, width as usize, height as usize, rgb);
+            0
+        }
+        None => ERROR,
+    }
+}
+
+fn write_text([x, y, text, len, _]: [u64; 5]) -> u64 {
+    let Some(text) = user_str(text, len) else {
+        return ERROR;
+    };
+    match SCREEN.lock().as_mut() {
+        Some(screen) => match screen.write_text(x as usize, y as usize, text) {
+            Ok(()) => 0,
+            Err(_) => ERROR,
+        },
+        None => ERROR,
+    }
+}
+
+fn write_console([text, len, ..]: [u64; 5]) -> u64 {
+    let Some(text) = user_str(text, len) else {
+        return ERROR;
+    };
+    serial_print!("{text}");
+    0
+}
+
+fn record_telemetry([text, len, ..]: [u64; 5]) -> u64 {
+    let Some(text) = user_str(text, len) else {
+        return ERROR;
+    };
+    telemetry::record(format_args!("{text}"));
+    0
+}
+
+fn read_message([buffer, capacity, ..]: [u64; 5]) -> u64 {
+    if !is_user_range(buffer, capacity) {
+        return ERROR;
+    }
+    let Some(message) = MESSAGES.lock().pop_front() else {
+        return NO_MESSAGE;
+    };
+    if message.len() as u64 > capacity {
+        return ERROR;
+    }
+    let buffer = unsafe { core::slice::from_raw_parts_mut(buffer as *mut u8, message.len()) };
+    buffer.copy_from_slice(message.as_bytes());
+    message.len() as u64
+}
+
+/// Returns the UTF-8 text a program passed, if it is no longer than `MAX_TEXT` and lies in
+/// its memory.
+fn user_str(text: u64, len: u64) -> Option<&'static str> {
+    if len > MAX_TEXT || !is_user_range(text, len) {
+        return None;
+    }
+    let bytes = unsafe { core::slice::from_raw_parts(text as *const u8, len as usize) };
+    core::str::from_utf8(bytes).ok()
+}
+
+/// Whether `start..start + len` lies in the user range and is mapped for ring 3 in the
+/// program's address space.
+fn is_user_range(start: u64, len: u64) -> bool {
+    match start.checked_add(len) {
+        Some(end) if start >= memory::USER_START && end <= memory::USER_END => {
+            len == 0 || memory::is_user_accessible(VirtAddr::new(start), len)
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,7 @@
+pub mod executor;
+mod keyboard;
+mod timer;
+
+pub use executor::spawn;
+pub use keyboard::KeyStream;
+pub use timer::Timer;
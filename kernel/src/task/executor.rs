@@ -0,0 +1,131 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Waker};
+use spin::Mutex;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+/// Tasks that are waiting, by id. A task is taken out while it is polled.
+static TASKS: Mutex<BTreeMap<u64, Task>> = Mutex::new(BTreeMap::new());
+/// Ids of the tasks that have been woken, in order
+static READY: Mutex<VecDeque<u64>> = Mutex::new(VecDeque::new());
+
+struct Task {
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
+    task_waker: Arc<TaskWaker>,
+    waker: Waker,
+}
+
+struct TaskWaker {
+    id: u64,
+    /// Whether the task is in `READY` already, so waking it again does nothing
+    queued: AtomicBool,
+}
+
+impl TaskWaker {
+    fn queue(&self) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            with_ready(|ready| ready.push_back(self.id));
+            crate::events::wake_loop();
+        }
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.queue();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.queue();
+    }
+}
+
+/// Runs `f` on the ready queue with interrupts disabled, so wakers can be called from anywhere.
+fn with_ready<R>(f: impl FnOnce(&mut VecDeque<u64>) -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| f(&mut READY.lock()))
+}
+
+/// Runs `f` on the task list with interrupts disabled.
+fn with_tasks<R>(f: impl FnOnce(&mut BTreeMap<u64, Task>) -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| f(&mut TASKS.lock()))
+}
+
+/// Starts running `future` as a task. Tasks are polled by the cpu loop, see `events::run`.
+pub fn spawn(future: impl Future<Output = ()> + Send + 'static) {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let waker = Arc::new(TaskWaker {
+        id,
+        queued: AtomicBool::new(false),
+    });
+    waker.queue();
+    let task = Task {
+        future: Box::pin(future),
+        task_waker: waker.clone(),
+        waker: Waker::from(waker),
+    };
+    with_tasks(|tasks| tasks.insert(id, task));
+}
+
+/// Whether some task has been woken and waits to be polled.
+pub fn has_ready() -> bool {
+    with_ready(|ready| !ready.is_empty())
+}
+
+/// Polls the tasks that have been woken. Tasks woken while this runs are left for the next
+/// call, so a task that keeps waking itself cannot starve the cpu loop.
+pub fn run_ready() {
+    for _ in 0..with_ready(|ready| ready.len()) {
+        let Some(id) = with_ready(|ready| ready.pop_front()) else {
+            return;
+        };
+        // a task that finished may have been woken before it did
+        let Some(mut task) = with_tasks(|tasks| tasks.remove(&id)) else {
+            continue;
+        };
+        // wakes from here on queue the task again
+        task.task_waker.queued.store(false, Ordering::Release);
+        let mut context = Context::from_waker(&task.waker);
+        if task.future.as_mut().poll(&mut context).is_pending() {
+            with_tasks(|tasks| tasks.insert(id, task));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::future;
+    use core::task::Poll;
+
+    #[test_case]
+    fn woken_task_runs_to_completion() {
+        static DONE: AtomicBool = AtomicBool::new(false);
+        static WAKER: Mutex<Option<Waker>> = Mutex::new(None);
+        spawn(async {
+            let mut polled = false;
+            future::poll_fn(|cx| {
+                if polled {
+                    return Poll::Ready(());
+                }
+                polled = true;
+                *WAKER.lock() = Some(cx.waker().clone());
+                Poll::Pending
+            })
+            .await;
+            DONE.store(true, Ordering::Relaxed);
+        });
+        run_ready();
+        assert!(!DONE.load(Ordering::Relaxed));
+        assert!(!has_ready());
+
+        WAKER.lock().take().unwrap().wake();
+        assert!(has_ready());
+        run_ready();
+        assert!(DONE.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,92 @@
+use crate::handlers::{self, HandlerId};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::future::{self, Future};
+use core::task::{Context, Poll, Waker};
+use pc_keyboard::DecodedKey;
+use spin::Mutex;
+
+/// Keys a stream holds on to before it drops new ones
+const CAPACITY: usize = 100;
+
+/// Stream of the keys typed from the moment it was created.
+pub struct KeyStream {
+    shared: Arc<Mutex<Keys>>,
+    handler: HandlerId,
+}
+
+struct Keys {
+    queue: VecDeque<DecodedKey>,
+    /// Waker of the task awaiting the next key
+    waker: Option<Waker>,
+}
+
+impl KeyStream {
+    pub fn new() -> Self {
+        let shared = Arc::new(Mutex::new(Keys {
+            queue: VecDeque::new(),
+            waker: None,
+        }));
+        let keys = shared.clone();
+        let handler = handlers::on_keyboard(move |key| {
+            let mut keys = keys.lock();
+            if keys.queue.len() < CAPACITY {
+                keys.queue.push_back(key);
+            }
+            if let Some(waker) = keys.waker.take() {
+                waker.wake();
+            }
+        });
+        KeyStream { shared, handler }
+    }
+
+    /// Waits for the next key.
+    pub fn next_key(&mut self) -> impl Future<Output = DecodedKey> + '_ {
+        future::poll_fn(|cx| self.poll_next(cx))
+    }
+
+    /// Returns the next key if there is one, and otherwise wakes the task once there is.
+    pub fn poll_next(&mut self, cx: &mut Context) -> Poll<DecodedKey> {
+        let mut keys = self.shared.lock();
+        match keys.queue.pop_front() {
+            Some(key) => Poll::Ready(key),
+            None => {
+                keys.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Default for KeyStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for KeyStream {
+    fn drop(&mut self) {
+        handlers::remove(self.handler);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::executor;
+
+    #[test_case]
+    fn key_stream_wakes_its_task() {
+        static KEY: Mutex<Option<DecodedKey>> = Mutex::new(None);
+        let mut keys = KeyStream::new();
+        executor::spawn(async move {
+            *KEY.lock() = Some(keys.next_key().await);
+        });
+        executor::run_ready();
+        assert_eq!(*KEY.lock(), None);
+
+        handlers::handle_keyboard(DecodedKey::Unicode('a'));
+        executor::run_ready();
+        assert_eq!(*KEY.lock(), Some(DecodedKey::Unicode('a')));
+    }
+}
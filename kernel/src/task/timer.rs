@@ -0,0 +1,85 @@
+use crate::time;
+use crate::timers::{self, TimerId};
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+
+/// Future that completes once a number of timer ticks have passed.
+pub struct Timer {
+    /// Tick at which the future completes
+    deadline: u64,
+    /// Waker of the task awaiting the timer, woken by the timer callback
+    waker: Arc<Mutex<Option<Waker>>>,
+    timer: Option<TimerId>,
+}
+
+impl Timer {
+    /// Completes `ms` milliseconds from now, rounded up to whole ticks.
+    pub fn after(ms: u64) -> Self {
+        Self::after_ticks(time::ms_to_ticks(ms))
+    }
+
+    /// Completes `ticks` timer ticks from now.
+    pub fn after_ticks(ticks: u64) -> Self {
+        Timer {
+            deadline: time::ticks().saturating_add(ticks),
+            waker: Arc::new(Mutex::new(None)),
+            timer: None,
+        }
+    }
+}
+
+impl Future for Timer {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let now = time::ticks();
+        if now >= self.deadline {
+            return Poll::Ready(());
+        }
+        *self.waker.lock() = Some(cx.waker().clone());
+        if self.timer.is_none() {
+            let waker = self.waker.clone();
+            self.timer = Some(timers::after_ticks(self.deadline - now, move || {
+                if let Some(waker) = waker.lock().take() {
+                    waker.wake();
+                }
+            }));
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        if let Some(timer) = self.timer {
+            timers::cancel(timer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events;
+    use crate::task::executor;
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    #[test_case]
+    fn timer_fires_after_its_ticks() {
+        static FIRED_AT: AtomicU64 = AtomicU64::new(0);
+        let start = time::ticks();
+        executor::spawn(async {
+            Timer::after_ticks(3).await;
+            FIRED_AT.store(time::ticks(), Ordering::Relaxed);
+        });
+        while FIRED_AT.load(Ordering::Relaxed) == 0 {
+            events::dispatch_pending();
+            executor::run_ready();
+            x86_64::instructions::hlt();
+        }
+        assert!(FIRED_AT.load(Ordering::Relaxed) >= start + 3);
+    }
+}
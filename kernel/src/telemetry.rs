@@ -0,0 +1,46 @@
+use crate::boot_flags;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use uart_16550::SerialPort;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// I/O port of COM2
+const COM2: u16 = 0x2F8;
+
+lazy_static! {
+    /// Off unless the host passed the `telemetry` boot flag, since every record waits on the
+    /// UART with interrupts disabled.
+    static ref ENABLED: AtomicBool = AtomicBool::new(boot_flags::is_set("telemetry"));
+
+    /// COM2, initialised on first use. Its interrupt is left unrouted, it is only written to.
+    static ref SERIAL2: Mutex<SerialPort> = {
+        let mut port = unsafe { SerialPort::new(COM2) };
+        port.init();
+        Mutex::new(port)
+    };
+}
+
+/// Sends one record on COM2, the telemetry channel, as a line of its own. Records are meant
+/// for tools on the host, so they should be machine readable, like one JSON object per line.
+/// Does nothing while telemetry is disabled.
+pub fn record(args: fmt::Arguments) {
+    if !is_enabled() {
+        return;
+    }
+    without_interrupts(|| {
+        let mut port = SERIAL2.lock();
+        let _ = port.write_fmt(args);
+        let _ = port.write_char('\n');
+    });
+}
+
+/// Turns the records on or off, whatever the boot flag said.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
@@ -0,0 +1,198 @@
+use crate::{allocator, exit_qemu, gdt, interrupts, memory, serial_print, serial_println};
+use crate::{thread, time};
+use crate::{QemuExitCode, RacyCell};
+use bootloader_api::BootInfo;
+use core::fmt;
+use core::panic::PanicInfo;
+
+/// Timer interrupts per second while the tests run
+pub const TIMER_FREQUENCY: u32 = 100;
+
+/// Progress of the test run, kept where the panic handler can pick it up
+static RUN: RacyCell<Option<Run>> = RacyCell::new(None);
+
+struct Run {
+    total: usize,
+    passed: usize,
+    running: Option<&'static dyn Testable>,
+}
+
+/// A test for `test_runner`. Functions marked `#[test_case]` are tests, and so are the ones
+/// declared with `should_panic!`.
+pub trait Testable: Sync {
+    fn name(&self) -> &'static str;
+
+    fn run(&self);
+
+    /// Whether the test passes by panicking rather than by returning.
+    fn should_panic(&self) -> bool {
+        false
+    }
+}
+
+impl<T: Fn() + Sync> Testable for T {
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+
+    fn run(&self) {
+        self()
+    }
+}
+
+/// A test that passes only if it panics, see `should_panic!`.
+pub struct ShouldPanic {
+    pub name: &'static str,
+    pub test: fn(),
+}
+
+impl Testable for ShouldPanic {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn run(&self) {
+        (self.test)()
+    }
+
+    fn should_panic(&self) -> bool {
+        true
+    }
+}
+
+/// Declares a test that passes only if it panics. `#[should_panic]` is ignored under
+/// `custom_test_frameworks`, so use this instead of `#[test_case]`:
+///
+/// ```ignore
+/// kernel::should_panic! {
+///     fn zero_frequency() {
+///         time::set_frequency(0);
+///     }
+/// }
+/// ```
+///
+/// The panic ends the run, so a test binary holds at most one of these, and it runs last.
+/// Others go in test binaries of their own, like the integration tests in `kernel/tests`.
+#[macro_export]
+macro_rules! should_panic {
+    (fn $name:ident() $body:block) => {
+        #[test_case]
+        #[allow(non_upper_case_globals)]
+        const $name: $crate::testing::ShouldPanic = $crate::testing::ShouldPanic {
+            name: concat!(module_path!(), "::", stringify!($name)),
+            test: {
+                fn $name() $body
+                $name
+            },
+        };
+    };
+}
+
+/// Sets up a test binary other than the kernel's: memory and the heap from the boot info, then
+/// the rest of `init`. Call it from the `entry_point!` function, with
+/// `kernel::BOOTLOADER_CONFIG` as the config, before the tests run.
+pub fn boot(boot_info: &'static mut BootInfo) {
+    let physical_offset = boot_info.physical_memory_offset.into_option().unwrap();
+    unsafe { memory::init(physical_offset, &boot_info.memory_regions) };
+    allocator::init_heap();
+    init();
+}
+
+/// Loads the GDT and the IDT, so that a test causing a CPU exception fails instead of resetting
+/// the machine, and starts threads with the timer switching between them at
+/// `TIMER_FREQUENCY`. Call it before the tests run, once the heap is set up.
+pub fn init() {
+    gdt::init();
+    thread::init();
+    interrupts::init_idt();
+    interrupts::init_pics();
+    time::set_frequency(TIMER_FREQUENCY);
+    x86_64::instructions::interrupts::enable();
+}
+
+/// Runs the tests collected by `custom_test_frameworks`, reporting each one on serial, then ends
+/// the QEMU run with `QemuExitCode::Success` if they all passed or `Failed` otherwise.
+/// Set it as the crate's `#![test_runner]`.
+///
+/// Without unwinding, the first test that fails ends the run, since the tests after it could
+/// not trust the locks and the stack it left behind.
+pub fn test_runner(tests: &'static [&'static dyn Testable]) -> ! {
+    serial_println!("Running {} tests", tests.len());
+    let mut should_panic = tests.iter().filter(|test| test.should_panic());
+    let last = should_panic.next();
+    if let (Some(first), Some(second)) = (last, should_panic.next()) {
+        serial_println!(
+            "Error: {} and {} both panic to pass, give one a test binary of its own",
+            first.name(),
+            second.name()
+        );
+        exit_qemu(QemuExitCode::Failed);
+    }
+    *unsafe { RUN.get_mut() } = Some(Run {
+        total: tests.len(),
+        passed: 0,
+        running: None,
+    });
+
+    for &test in tests.iter().filter(|test| !test.should_panic()).chain(last) {
+        serial_print!("{}...\t", test.name());
+        unsafe { RUN.get_mut() }.as_mut().unwrap().running = Some(test);
+        test.run();
+        if test.should_panic() {
+            abort(format_args!("did not panic"));
+        }
+        serial_println!("[ok]");
+        unsafe { RUN.get_mut() }.as_mut().unwrap().passed += 1;
+    }
+    finish()
+}
+
+/// Reports that every test passed and ends the QEMU run.
+fn finish() -> ! {
+    let run = unsafe { RUN.get_mut() }.as_ref().unwrap();
+    serial_println!("All {} tests passed", run.total);
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// Reports the running test as failed and ends the QEMU run.
+fn abort(error: fmt::Arguments) -> ! {
+    let run = unsafe { RUN.get_mut() }.as_ref().unwrap();
+    serial_println!("[failed]\n\nError: {error}\n");
+    serial_println!(
+        "Run aborted: {} passed, 1 failed, {} not run",
+        run.passed,
+        run.total - run.passed - 1
+    );
+    exit_qemu(QemuExitCode::Failed);
+}
+
+/// Whether `test_runner` has started.
+pub(crate) fn is_running() -> bool {
+    unsafe { RUN.get_mut() }.is_some()
+}
+
+/// Called by the panic handler. While tests run, the panic comes from the running test: a
+/// `should_panic` test passes and any other fails, and either way the run ends there.
+/// Returns if no tests are running.
+pub(crate) fn on_panic(info: &PanicInfo) {
+    let Some(test) = unsafe { RUN.get_mut() }
+        .as_ref()
+        .and_then(|run| run.running)
+    else {
+        return;
+    };
+    if test.should_panic() {
+        serial_println!("[ok]");
+        unsafe { RUN.get_mut() }.as_mut().unwrap().passed += 1;
+        finish();
+    }
+    abort(format_args!("{info}"));
+}
+
+/// Called for an exception the kernel cannot recover from. While tests run, it fails the
+/// running test and ends the run. Returns if no tests are running.
+pub(crate) fn on_fatal_exception(report: fmt::Arguments) {
+    if is_running() {
+        abort(report);
+    }
+}
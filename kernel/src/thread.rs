@@ -0,0 +1,545 @@
+use crate::{gdt, memory, time};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::mem::size_of;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::segmentation::{Segment, CS, SS};
+use x86_64::structures::paging::{PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+
+/// Interrupt vector `yield_now` raises to switch threads
+pub(crate) const YIELD_VECTOR: u8 = 0x81;
+
+/// Virtual range thread stacks are mapped in. Each slot holds a stack with an unmapped guard
+/// page below it, so an overflow page faults instead of running into the next stack.
+const STACKS_START: u64 = 0x_6666_0000_0000;
+const STACK_SLOT_SIZE: u64 = 0x10_0000;
+const MAX_THREADS: u64 = 1024;
+/// Stack size of spawned threads
+pub const STACK_SIZE: u64 = 64 * 1024;
+
+static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+
+/// Identifies a thread. The thread that booted the kernel is thread 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ready,
+    /// Waiting for the given tick
+    Sleeping(u64),
+    /// Waiting for the given thread to finish
+    Joining(ThreadId),
+    /// Waiting for `unpark`
+    Parked,
+    Finished,
+}
+
+struct Thread {
+    id: ThreadId,
+    state: State,
+    /// Stack pointer saved when the thread was switched away from, pointing at its registers
+    rsp: u64,
+    /// Bottom of the stack slot, `None` for the boot thread, which uses the bootloader's stack
+    stack: Option<u64>,
+    /// Closure run by a thread that has not started yet
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    /// Stack for interrupts arriving while the thread runs in ring 3
+    kernel_stack: Option<u64>,
+    /// Level 4 table of the user program the thread runs, `None` for the kernel's
+    address_space: Option<PhysFrame>,
+}
+
+struct Scheduler {
+    threads: Vec<Thread>,
+    current: ThreadId,
+    /// Thread that runs when no other thread is ready
+    idle: ThreadId,
+    next_id: u64,
+    /// Stack slots that have been mapped, by threads that are gone
+    free_stacks: Vec<u64>,
+    /// Next stack slot that has never been used
+    next_stack: u64,
+}
+
+/// Registers the way `context_switch` pushes them, below the interrupt stack frame.
+#[repr(C)]
+pub(crate) struct SavedRegisters {
+    pub(crate) r15: u64,
+    pub(crate) r14: u64,
+    pub(crate) r13: u64,
+    pub(crate) r12: u64,
+    pub(crate) r11: u64,
+    pub(crate) r10: u64,
+    pub(crate) r9: u64,
+    pub(crate) r8: u64,
+    pub(crate) rbp: u64,
+    pub(crate) rdi: u64,
+    pub(crate) rsi: u64,
+    pub(crate) rdx: u64,
+    pub(crate) rcx: u64,
+    pub(crate) rbx: u64,
+    pub(crate) rax: u64,
+    // interrupt stack frame
+    pub(crate) rip: u64,
+    pub(crate) cs: u64,
+    pub(crate) rflags: u64,
+    pub(crate) rsp: u64,
+    pub(crate) ss: u64,
+}
+
+impl Scheduler {
+    fn index(&self, id: ThreadId) -> Option<usize> {
+        self.threads.iter().position(|thread| thread.id == id)
+    }
+
+    /// Returns a stack slot with its stack mapped.
+    fn allocate_stack(&mut self) -> u64 {
+        if let Some(stack) = self.free_stacks.pop() {
+            return stack;
+        }
+        assert!(self.next_stack < MAX_THREADS, "too many threads");
+        let slot = STACKS_START + self.next_stack * STACK_SLOT_SIZE;
+        self.next_stack += 1;
+        // the first page of the slot stays unmapped as the guard page
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        memory::map_pages(VirtAddr::new(slot + 4096), STACK_SIZE, flags)
+            .expect("cannot map a thread stack");
+        slot
+    }
+
+    fn add(&mut self, entry: Box<dyn FnOnce() + Send>) -> ThreadId {
+        let id = ThreadId(self.next_id);
+        self.next_id += 1;
+        let stack = self.allocate_stack();
+        let top = stack + 4096 + STACK_SIZE;
+        // start as if returning from an interrupt into `thread_start`, which gets a 16-byte
+        // aligned stack minus the return address a call would have pushed
+        let rsp = top - size_of::<SavedRegisters>() as u64;
+        unsafe {
+            (rsp as *mut SavedRegisters).write(SavedRegisters {
+                r15: 0,
+                r14: 0,
+                r13: 0,
+                r12: 0,
+                r11: 0,
+                r10: 0,
+                r9: 0,
+                r8: 0,
+                rbp: 0,
+                rdi: 0,
+                rsi: 0,
+                rdx: 0,
+                rcx: 0,
+                rbx: 0,
+                rax: 0,
+                rip: thread_start as *const () as u64,
+                cs: CS::get_reg().0 as u64,
+                // interrupts enabled
+                rflags: 0x202,
+                rsp: top - 8,
+                ss: SS::get_reg().0 as u64,
+            });
+        }
+        self.threads.push(Thread {
+            id,
+            state: State::Ready,
+            rsp,
+            stack: Some(stack),
+            entry: Some(entry),
+            kernel_stack: None,
+            address_space: None,
+        });
+        id
+    }
+
+    /// Saves the current thread's stack pointer and picks the thread to run next, round robin.
+    fn switch(&mut self, rsp: u64) -> u64 {
+        let current = self.current;
+        // finished threads are no longer running on their stacks, except the current one. The
+        // others keep their order, which is the order they take turns in.
+        let mut i = 0;
+        while i < self.threads.len() {
+            let thread = &self.threads[i];
+            if thread.state == State::Finished && thread.id != current {
+                if let Some(stack) = self.threads.remove(i).stack {
+                    self.free_stacks.push(stack);
+                }
+            } else {
+                i += 1;
+            }
+        }
+
+        let now = time::ticks();
+        for thread in self.threads.iter_mut() {
+            match thread.state {
+                State::Sleeping(until) if until <= now => thread.state = State::Ready,
+                _ => {}
+            }
+        }
+
+        let index = self.index(current).expect("current thread is gone");
+        self.threads[index].rsp = rsp;
+        let len = self.threads.len();
+        let next = (1..=len)
+            .map(|offset| (index + offset) % len)
+            .find(|&i| self.threads[i].state == State::Ready && self.threads[i].id != self.idle)
+            .unwrap_or_else(|| self.index(self.idle).unwrap());
+        self.current = self.threads[next].id;
+        if let Some(kernel_stack) = self.threads[next].kernel_stack {
+            gdt::set_kernel_stack(VirtAddr::new(kernel_stack));
+        }
+        memory::activate(self.threads[next].address_space);
+        self.threads[next].rsp
+    }
+}
+
+/// Runs `f` on the scheduler with interrupts disabled, so the timer cannot switch threads while
+/// it is locked. Panics if `init` has not been called.
+fn with_scheduler<R>(f: impl FnOnce(&mut Scheduler) -> R) -> R {
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        f(scheduler
+            .as_mut()
+            .expect("threads are started by HandlerTable::start"))
+    })
+}
+
+/// Makes the running code the boot thread and starts the idle thread.
+pub(crate) fn init() {
+    let boot = Thread {
+        id: ThreadId(0),
+        state: State::Ready,
+        rsp: 0,
+        stack: None,
+        entry: None,
+        kernel_stack: None,
+        address_space: None,
+    };
+    let mut scheduler = Scheduler {
+        threads: Vec::from([boot]),
+        current: ThreadId(0),
+        idle: ThreadId(1),
+        next_id: 1,
+        free_stacks: Vec::new(),
+        next_stack: 0,
+    };
+    scheduler.idle = scheduler.add(Box::new(|| idle()));
+    without_interrupts(|| *SCHEDULER.lock() = Some(scheduler));
+}
+
+/// Called by the timer interrupt and `yield_now` with the stack pointer of the interrupted
+/// thread, returns the stack pointer of the thread to resume.
+pub(crate) fn switch(rsp: u64) -> u64 {
+    match SCHEDULER.lock().as_mut() {
+        Some(scheduler) => scheduler.switch(rsp),
+        None => rsp,
+    }
+}
+
+/// Saves the registers of the interrupted thread on its stack, lets `$switch` pick the thread
+/// to resume from its stack pointer, and restores that thread's registers.
+macro_rules! context_switch {
+    ($switch:path) => {
+        ::core::arch::naked_asm!(
+            "push rax",
+            "push rbx",
+            "push rcx",
+            "push rdx",
+            "push rsi",
+            "push rdi",
+            "push rbp",
+            "push r8",
+            "push r9",
+            "push r10",
+            "push r11",
+            "push r12",
+            "push r13",
+            "push r14",
+            "push r15",
+            // the CPU aligned the stack to 16 bytes before pushing the 5-word interrupt frame,
+            // and 15 registers have been pushed since, so it is aligned again for the call
+            "mov rdi, rsp",
+            "call {switch}",
+            "mov rsp, rax",
+            "pop r15",
+            "pop r14",
+            "pop r13",
+            "pop r12",
+            "pop r11",
+            "pop r10",
+            "pop r9",
+            "pop r8",
+            "pop rbp",
+            "pop rdi",
+            "pop rsi",
+            "pop rdx",
+            "pop rcx",
+            "pop rbx",
+            "pop rax",
+            "iretq",
+            switch = sym $switch,
+        )
+    };
+}
+pub(crate) use context_switch;
+
+/// Sets the stack interrupts use while the running thread is in ring 3, or clears it when the
+/// thread is back in the kernel.
+pub(crate) fn set_kernel_stack(kernel_stack: Option<u64>) {
+    with_scheduler(|scheduler| {
+        let index = scheduler.index(scheduler.current).unwrap();
+        scheduler.threads[index].kernel_stack = kernel_stack;
+        if let Some(kernel_stack) = kernel_stack {
+            gdt::set_kernel_stack(VirtAddr::new(kernel_stack));
+        }
+    });
+}
+
+/// Switches the running thread to the address space whose level 4 table is in `p4`, or back to
+/// the kernel's with `None`. It stays active whenever the thread runs.
+pub(crate) fn set_address_space(p4: Option<PhysFrame>) {
+    with_scheduler(|scheduler| {
+        let index = scheduler.index(scheduler.current).unwrap();
+        scheduler.threads[index].address_space = p4;
+        memory::activate(p4);
+    });
+}
+
+/// Entry of the timer interrupt, which switches threads on every tick.
+#[unsafe(naked)]
+pub(crate) extern "C" fn timer_entry() {
+    context_switch!(crate::interrupts::timer_interrupt)
+}
+
+/// Entry of the `yield_now` interrupt.
+#[unsafe(naked)]
+pub(crate) extern "C" fn yield_entry() {
+    context_switch!(yield_interrupt)
+}
+
+extern "C" fn yield_interrupt(rsp: u64) -> u64 {
+    switch(rsp)
+}
+
+/// First code run by a spawned thread.
+extern "C" fn thread_start() -> ! {
+    let entry = with_scheduler(|scheduler| {
+        let index = scheduler.index(scheduler.current).unwrap();
+        scheduler.threads[index].entry.take()
+    });
+    if let Some(entry) = entry {
+        entry();
+    }
+    with_scheduler(|scheduler| {
+        let current = scheduler.current;
+        for thread in scheduler.threads.iter_mut() {
+            if thread.state == State::Joining(current) {
+                thread.state = State::Ready;
+            }
+        }
+        let index = scheduler.index(current).unwrap();
+        scheduler.threads[index].state = State::Finished;
+    });
+    yield_now();
+    unreachable!("a finished thread was resumed");
+}
+
+/// Handle to wait for a spawned thread and collect what it returned.
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Blocks until the thread has finished and returns its result.
+    pub fn join(self) -> T {
+        wait_for(self.id);
+        self.result
+            .lock()
+            .take()
+            .expect("the thread finished without a result")
+    }
+}
+
+/// Starts a thread running `f`, with its own stack of `STACK_SIZE` bytes. Threads take turns
+/// on every timer tick. Must be called after `HandlerTable::start`, for example from a handler,
+/// and needs `memory::init` to have been called to map the stack.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let result = Arc::new(Mutex::new(None));
+    let slot = result.clone();
+    let entry = Box::new(move || {
+        let value = f();
+        *slot.lock() = Some(value);
+    });
+    let id = with_scheduler(|scheduler| scheduler.add(entry));
+    JoinHandle { id, result }
+}
+
+/// Returns the id of the running thread.
+pub fn current() -> ThreadId {
+    with_scheduler(|scheduler| scheduler.current)
+}
+
+/// Gives the rest of the time slice to the next ready thread.
+pub fn yield_now() {
+    unsafe { asm!("int {}", const YIELD_VECTOR) };
+}
+
+/// Blocks the running thread for `ms` milliseconds, rounded up to whole ticks.
+pub fn sleep(ms: u64) {
+    let until = time::ticks().saturating_add(time::ms_to_ticks(ms));
+    with_scheduler(|scheduler| {
+        let index = scheduler.index(scheduler.current).unwrap();
+        scheduler.threads[index].state = State::Sleeping(until);
+    });
+    yield_now();
+}
+
+/// Blocks the running thread until `unpark` is called for it. An `unpark` that comes before the
+/// thread has switched away is not lost, the thread then stays ready.
+pub(crate) fn park() {
+    with_scheduler(|scheduler| {
+        let index = scheduler.index(scheduler.current).unwrap();
+        scheduler.threads[index].state = State::Parked;
+    });
+    yield_now();
+}
+
+/// Makes the thread `id` ready again if it is parked. Can be called from interrupt handlers.
+pub(crate) fn unpark(id: ThreadId) {
+    with_scheduler(|scheduler| {
+        if let Some(index) = scheduler.index(id) {
+            if scheduler.threads[index].state == State::Parked {
+                scheduler.threads[index].state = State::Ready;
+            }
+        }
+    });
+}
+
+/// Runs when no other thread is ready. Halts until the next interrupt, then yields, so that a
+/// thread the interrupt woke runs right away rather than at the next tick.
+fn idle() -> ! {
+    loop {
+        x86_64::instructions::hlt();
+        yield_now();
+    }
+}
+
+/// Blocks until the thread `id` has finished.
+fn wait_for(id: ThreadId) {
+    let waiting = with_scheduler(|scheduler| {
+        let running = scheduler
+            .index(id)
+            .is_some_and(|index| scheduler.threads[index].state != State::Finished);
+        if running {
+            let index = scheduler.index(scheduler.current).unwrap();
+            scheduler.threads[index].state = State::Joining(id);
+        }
+        running
+    });
+    if waiting {
+        yield_now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test_case]
+    fn join_returns_what_the_thread_returned() {
+        let handle = spawn(|| 6 * 7);
+        assert_ne!(handle.id(), current());
+        assert_eq!(handle.join(), 42);
+    }
+
+    #[test_case]
+    fn spawned_threads_get_their_own_ids() {
+        let first = spawn(current);
+        let second = spawn(current);
+        let (first_id, second_id) = (first.id(), second.id());
+        assert_ne!(first_id, second_id);
+        assert_eq!(first.join(), first_id);
+        assert_eq!(second.join(), second_id);
+    }
+
+    #[test_case]
+    fn sleep_wakes_up_after_the_delay() {
+        let start = time::ticks();
+        let handle = spawn(move || {
+            sleep(30);
+            time::ticks()
+        });
+        assert!(handle.join() >= start + time::ms_to_ticks(30));
+    }
+
+    #[test_case]
+    fn sleeping_for_ever_does_not_overflow() {
+        assert_eq!(
+            time::ms_to_ticks(u64::MAX),
+            u64::MAX.div_ceil(time::tick_period())
+        );
+        let handle = spawn(|| sleep(u64::MAX));
+        for _ in 0..3 {
+            yield_now();
+        }
+        assert!(handle.result.lock().is_none());
+    }
+
+    #[test_case]
+    fn threads_take_turns_in_spawn_order() {
+        let order = Arc::new(Mutex::new(Vec::new()));
+        // spawn them all before any of them runs
+        let handles: Vec<_> = without_interrupts(|| {
+            (0..3)
+                .map(|n| {
+                    let order = order.clone();
+                    spawn(move || {
+                        for _ in 0..2 {
+                            // keep the timer from switching between the push and the yield
+                            without_interrupts(|| {
+                                order.lock().push(n);
+                                yield_now();
+                            });
+                        }
+                    })
+                })
+                .collect()
+        });
+        for handle in handles {
+            handle.join();
+        }
+        assert_eq!(*order.lock(), vec![0, 1, 2, 0, 1, 2]);
+    }
+
+    #[test_case]
+    fn parked_threads_wait_for_unpark() {
+        let woken = Arc::new(Mutex::new(false));
+        let flag = woken.clone();
+        let handle = spawn(move || {
+            park();
+            *flag.lock() = true;
+        });
+        for _ in 0..3 {
+            yield_now();
+        }
+        assert!(!*woken.lock());
+        unpark(handle.id());
+        handle.join();
+        assert!(*woken.lock());
+    }
+}
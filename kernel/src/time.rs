@@ -0,0 +1,200 @@
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::port::Port;
+
+/// Input clock of the PIT, in Hz
+pub const PIT_FREQUENCY: u32 = 1_193_182;
+/// Divisor the firmware leaves the PIT at, giving about 18.2 Hz
+const DEFAULT_DIVISOR: u32 = 65536;
+/// How long the TSC is measured against the PIT
+const TSC_CALIBRATION_MS: u32 = 50;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static UPTIME_NS: AtomicU64 = AtomicU64::new(0);
+static TICK_NS: AtomicU64 = AtomicU64::new(tick_ns(DEFAULT_DIVISOR));
+/// TSC increments per second, 0 until `calibrate_tsc` has run
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+/// TSC value that `now_ns` counts from
+static TSC_START: AtomicU64 = AtomicU64::new(0);
+
+const fn tick_ns(divisor: u32) -> u64 {
+    divisor as u64 * 1_000_000_000 / PIT_FREQUENCY as u64
+}
+
+/// Programs PIT channel 0 to fire `hz` times per second. The rate is rounded to the closest one
+/// the PIT supports, which ranges from about 19 Hz to 1.19 MHz.
+pub(crate) fn set_frequency(hz: u32) {
+    assert!(hz > 0, "timer frequency must not be zero");
+    let divisor = ((PIT_FREQUENCY + hz / 2) / hz).clamp(1, DEFAULT_DIVISOR);
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel_0: Port<u8> = Port::new(0x40);
+    unsafe {
+        // channel 0, low byte then high byte, square wave generator
+        command.write(0x36);
+        // a divisor of 65536 is written as 0
+        channel_0.write(divisor as u8);
+        channel_0.write((divisor >> 8) as u8);
+    }
+    TICK_NS.store(tick_ns(divisor), Ordering::Relaxed);
+}
+
+/// Returns the time between two timer interrupts, in nanoseconds.
+pub(crate) fn tick_period() -> u64 {
+    TICK_NS.load(Ordering::Relaxed)
+}
+
+/// Records the time between two timer interrupts, for timers other than the PIT.
+pub(crate) fn set_tick_period(ns: u64) {
+    TICK_NS.store(ns, Ordering::Relaxed);
+}
+
+/// Busy-waits for `ms` milliseconds, at most 54, using PIT channel 2. Works with interrupts
+/// disabled and leaves channel 0, the timer interrupt, alone.
+pub(crate) fn pit_delay(ms: u32) {
+    let count = (PIT_FREQUENCY as u64 * ms.min(54) as u64 / 1000) as u16;
+    let mut gate: Port<u8> = Port::new(0x61);
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel_2: Port<u8> = Port::new(0x42);
+    unsafe {
+        // gate channel 2 on, keep the speaker off
+        let value = gate.read();
+        gate.write((value & !0x02) | 0x01);
+        // channel 2, low byte then high byte, interrupt on terminal count
+        command.write(0xb0);
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8);
+        // the channel's output shows up in bit 5 once the count has run out
+        while gate.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Called by the timer interrupt.
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    UPTIME_NS.fetch_add(TICK_NS.load(Ordering::Relaxed), Ordering::Relaxed);
+}
+
+/// Number of timer interrupts since interrupts were enabled.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Timer interrupts per second, rounded to whole Hz.
+pub fn frequency() -> u32 {
+    (1_000_000_000 / TICK_NS.load(Ordering::Relaxed)) as u32
+}
+
+/// Time elapsed since interrupts were enabled, with the resolution of one tick.
+pub fn uptime() -> Duration {
+    Duration::from_nanos(UPTIME_NS.load(Ordering::Relaxed))
+}
+
+/// Converts milliseconds to timer ticks at the current frequency, rounding up. Delays too long
+/// to count in nanoseconds saturate rather than overflow.
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    let tick_ns = TICK_NS.load(Ordering::Relaxed);
+    ms.saturating_mul(1_000_000).div_ceil(tick_ns)
+}
+
+//- TSC clock
+
+/// Measures the TSC against the PIT, so that `now_ns` can turn it into nanoseconds.
+/// Takes about 50 ms.
+pub(crate) fn calibrate_tsc() {
+    let start = rdtsc();
+    pit_delay(TSC_CALIBRATION_MS);
+    let elapsed = rdtsc() - start;
+    TSC_HZ.store(
+        elapsed * 1000 / TSC_CALIBRATION_MS as u64,
+        Ordering::Relaxed,
+    );
+    TSC_START.store(start, Ordering::Relaxed);
+}
+
+fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// TSC increments per second, or 0 if the TSC has not been calibrated.
+pub fn tsc_frequency() -> u64 {
+    TSC_HZ.load(Ordering::Relaxed)
+}
+
+/// Nanoseconds since `HandlerTable::start` was called, read from the TSC. Before the TSC is
+/// calibrated, falls back to `uptime` and its resolution of one tick.
+pub fn now_ns() -> u64 {
+    let hz = TSC_HZ.load(Ordering::Relaxed);
+    if hz == 0 {
+        return UPTIME_NS.load(Ordering::Relaxed);
+    }
+    let elapsed = rdtsc().wrapping_sub(TSC_START.load(Ordering::Relaxed));
+    (elapsed as u128 * 1_000_000_000 / hz as u128) as u64
+}
+
+/// Time elapsed since `HandlerTable::start` was called, read from the TSC.
+pub fn now() -> Duration {
+    Duration::from_nanos(now_ns())
+}
+
+/// Waits for `ms` milliseconds. See `Deadline::wait`.
+pub fn sleep_ms(ms: u64) {
+    Deadline::after_ms(ms).wait();
+}
+
+/// Waits for `duration`. See `Deadline::wait`.
+pub fn sleep(duration: Duration) {
+    Deadline::after(duration).wait();
+}
+
+/// A point in time on the `now_ns` clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Deadline(u64);
+
+impl Deadline {
+    /// The deadline `duration` from now.
+    pub fn after(duration: Duration) -> Self {
+        let ns = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        Deadline(now_ns().saturating_add(ns))
+    }
+
+    /// The deadline `ms` milliseconds from now.
+    pub fn after_ms(ms: u64) -> Self {
+        Self::after(Duration::from_millis(ms))
+    }
+
+    /// The deadline at `ns` on the `now_ns` clock.
+    pub fn at_ns(ns: u64) -> Self {
+        Deadline(ns)
+    }
+
+    /// Returns the deadline on the `now_ns` clock.
+    pub fn as_ns(&self) -> u64 {
+        self.0
+    }
+
+    /// Whether the deadline has been reached.
+    pub fn has_passed(&self) -> bool {
+        now_ns() >= self.0
+    }
+
+    /// Time left until the deadline, zero once it has passed.
+    pub fn remaining(&self) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(now_ns()))
+    }
+
+    /// Waits until the deadline has passed. With interrupts enabled the CPU halts until the next
+    /// interrupt between checks, so the wait may overshoot by up to one tick; otherwise it
+    /// busy-waits.
+    pub fn wait(&self) {
+        while !self.has_passed() {
+            if x86_64::instructions::interrupts::are_enabled() {
+                x86_64::instructions::hlt();
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use crate::time;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Number of slots in the wheel. A timer lives in the slot of its deadline tick modulo this, so
+/// each tick only looks at the timers of one slot.
+const WHEEL_SLOTS: usize = 64;
+
+static WHEEL: Mutex<Wheel> = Mutex::new(Wheel {
+    slots: [const { Vec::new() }; WHEEL_SLOTS],
+    next_id: 0,
+    current: 0,
+    running: None,
+});
+
+/// Handle to a registered timer, used to cancel it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(u64);
+
+struct Timer {
+    id: u64,
+    /// Tick at which the callback is due
+    deadline: u64,
+    /// Ticks between calls, for periodic timers
+    period: Option<u64>,
+    callback: Box<dyn FnMut() + Send>,
+}
+
+struct Wheel {
+    slots: [Vec<Timer>; WHEEL_SLOTS],
+    next_id: u64,
+    /// Next tick whose slot has to be looked at
+    current: u64,
+    /// Timer whose callback is running, outside the wheel, and whether it was cancelled meanwhile
+    running: Option<(u64, bool)>,
+}
+
+impl Wheel {
+    fn insert(&mut self, mut timer: Timer) {
+        timer.deadline = timer.deadline.max(self.current);
+        self.slots[timer.deadline as usize % WHEEL_SLOTS].push(timer);
+    }
+
+    /// Takes out the next timer that is due at or before `now`.
+    fn pop_expired(&mut self, now: u64) -> Option<Timer> {
+        while self.current <= now {
+            let current = self.current;
+            let slot = &mut self.slots[current as usize % WHEEL_SLOTS];
+            if let Some(i) = slot.iter().position(|timer| timer.deadline <= current) {
+                let timer = slot.swap_remove(i);
+                self.running = Some((timer.id, false));
+                return Some(timer);
+            }
+            self.current += 1;
+        }
+        None
+    }
+}
+
+/// Runs `f` on the wheel with interrupts disabled, so the timer interrupt cannot deadlock on it.
+fn with_wheel<R>(f: impl FnOnce(&mut Wheel) -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| f(&mut WHEEL.lock()))
+}
+
+fn schedule(delay: u64, period: Option<u64>, callback: Box<dyn FnMut() + Send>) -> TimerId {
+    with_wheel(|wheel| {
+        let id = wheel.next_id;
+        wheel.next_id += 1;
+        wheel.insert(Timer {
+            id,
+            deadline: time::ticks().saturating_add(delay.max(1)),
+            period,
+            callback,
+        });
+        TimerId(id)
+    })
+}
+
+/// Calls `callback` once, `ticks` timer ticks from now.
+pub fn after_ticks(ticks: u64, callback: impl FnMut() + Send + 'static) -> TimerId {
+    schedule(ticks, None, Box::new(callback))
+}
+
+/// Calls `callback` once, `ms` milliseconds from now, rounded up to whole ticks.
+pub fn after_ms(ms: u64, callback: impl FnMut() + Send + 'static) -> TimerId {
+    after_ticks(time::ms_to_ticks(ms), callback)
+}
+
+/// Calls `callback` every `ticks` timer ticks, starting `ticks` from now.
+pub fn every_ticks(ticks: u64, callback: impl FnMut() + Send + 'static) -> TimerId {
+    let ticks = ticks.max(1);
+    schedule(ticks, Some(ticks), Box::new(callback))
+}
+
+/// Calls `callback` every `ms` milliseconds, rounded up to whole ticks.
+pub fn every_ms(ms: u64, callback: impl FnMut() + Send + 'static) -> TimerId {
+    every_ticks(time::ms_to_ticks(ms), callback)
+}
+
+/// Stops a timer. Returns false if it had already fired, as a one-shot timer, or been
+/// cancelled. A timer can cancel itself from its own callback.
+pub fn cancel(id: TimerId) -> bool {
+    let (cancelled, removed) = with_wheel(|wheel| {
+        if let Some((running, cancelled)) = &mut wheel.running {
+            if *running == id.0 {
+                return (!core::mem::replace(cancelled, true), None);
+            }
+        }
+        for slot in wheel.slots.iter_mut() {
+            if let Some(i) = slot.iter().position(|timer| timer.id == id.0) {
+                return (true, Some(slot.swap_remove(i)));
+            }
+        }
+        (false, None)
+    });
+    // drop the callback outside the lock, in case what it owns touches timers when dropped
+    drop(removed);
+    cancelled
+}
+
+/// Calls the callbacks of every timer due at or before `now`. Called for each timer event.
+/// Callbacks run without the wheel locked, so they can register and cancel timers.
+pub(crate) fn run(now: u64) {
+    while let Some(mut timer) = with_wheel(|wheel| wheel.pop_expired(now)) {
+        (timer.callback)();
+        let finished = with_wheel(|wheel| {
+            let cancelled = matches!(wheel.running.take(), Some((_, true)));
+            match timer.period {
+                Some(period) if !cancelled => {
+                    timer.deadline = timer.deadline.saturating_add(period);
+                    wheel.insert(timer);
+                    None
+                }
+                _ => Some(timer),
+            }
+        });
+        drop(finished);
+    }
+}
@@ -0,0 +1,26 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader_api::{entry_point, BootInfo};
+use kernel::logger;
+use log::LevelFilter;
+
+// A should_panic test ends the run, so it gets a test binary of its own.
+
+entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::testing::boot(boot_info);
+    test_main();
+    kernel::hlt_loop();
+}
+
+kernel::should_panic! {
+    fn second_init_panics() {
+        logger::init(LevelFilter::Info);
+        logger::init(LevelFilter::Info);
+    }
+}
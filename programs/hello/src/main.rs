@@ -0,0 +1,35 @@
+//! Shows a greeting from ring 3 and echoes typed characters until Escape is pressed.
+
+#![no_std] // don't link the Rust standard library
+#![no_main] // disable all Rust-level entry points
+
+use core::panic::PanicInfo;
+use userlib::Key;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    userlib::draw_rect(20, 20, 400, 80, 0xffffff);
+    userlib::draw_rect(22, 22, 396, 76, 0x000000);
+    userlib::write_text(40, 35, "Hello from ring 3!");
+    userlib::write_text(40, 60, "Type something, Esc quits: ");
+
+    let mut x = 40;
+    loop {
+        match userlib::read_key() {
+            Some(Key::Char('\u{1b}')) => break,
+            Some(Key::Char(c)) if x < 390 => {
+                let mut buffer = [0; 4];
+                userlib::write_text(x, 78, c.encode_utf8(&mut buffer));
+                x += 8;
+            }
+            _ => userlib::yield_now(),
+        }
+    }
+    userlib::draw_rect(20, 20, 400, 80, 0x000000);
+    userlib::exit(0)
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    userlib::exit(101)
+}
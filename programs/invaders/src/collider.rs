@@ -86,3 +86,69 @@ pub fn bullet_collider(position: &(i16, i16)) -> Collider {
         right: position.0 + BULLET_COLLIDER_SIZE.0,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collider(top: i16, bottom: i16, left: i16, right: i16) -> Collider {
+        Collider {
+            top,
+            bottom,
+            left,
+            right,
+        }
+    }
+
+    #[test]
+    fn overlapping_colliders_collide_both_ways() {
+        let a = collider(0, 10, 0, 10);
+        let b = collider(5, 15, 5, 15);
+        assert!(a.collides_with(&b));
+        assert!(b.collides_with(&a));
+    }
+
+    #[test]
+    fn contained_collider_collides() {
+        let outer = collider(0, 100, 0, 100);
+        let inner = collider(40, 60, 40, 60);
+        assert!(outer.collides_with(&inner));
+        assert!(inner.collides_with(&outer));
+    }
+
+    #[test]
+    fn touching_edges_do_not_collide() {
+        let a = collider(0, 10, 0, 10);
+        assert!(!a.collides_with(&collider(10, 20, 0, 10)));
+        assert!(!a.collides_with(&collider(0, 10, 10, 20)));
+    }
+
+    #[test]
+    fn separated_colliders_do_not_collide() {
+        let a = collider(0, 10, 0, 10);
+        assert!(!a.collides_with(&collider(0, 10, 50, 60)));
+        assert!(!a.collides_with(&collider(50, 60, 0, 10)));
+    }
+
+    #[test]
+    fn collider_is_above_its_position() {
+        let player = player_collider(&(100, 200));
+        assert_eq!(player.bottom, 200);
+        assert_eq!(player.top, 200 - PLAYER_COLLIDER_SIZE.1);
+        assert_eq!(player.right, 100 + PLAYER_COLLIDER_SIZE.0);
+    }
+
+    #[test]
+    fn collider_near_the_top_is_clamped() {
+        let bullet = bullet_collider(&(100, 10));
+        assert_eq!(bullet.top, 0);
+        assert_eq!(bullet.bottom, BULLET_COLLIDER_SIZE.1);
+    }
+
+    #[test]
+    fn player_bullet_hits_enemy() {
+        let enemy = enemy_collider(&(100, 95));
+        assert!(bullet_collider(&(120, 80)).collides_with(&enemy));
+        assert!(!bullet_collider(&(200, 80)).collides_with(&enemy));
+    }
+}
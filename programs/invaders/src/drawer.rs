@@ -1,23 +1,45 @@
-mod screen;
+//! Sprites and text of the game, drawn through the kernel's drawing syscalls.
 
-use bootloader_api::info::FrameBuffer;
-use screen::screenwriter;
-
-pub fn init(framebuffer: &'static mut FrameBuffer) {
-    screen::init(framebuffer);
-    screenwriter().clear();
-}
+use core::fmt::Write;
+use userlib::TextBuffer;
 
 pub fn clear_screen() {
-    screenwriter().clear();
+    // clipped to the screen by the kernel
+    userlib::draw_rect(0, 0, usize::MAX, usize::MAX, 0);
 }
 
 pub fn draw_rec(top_left: &(i16, i16), bottom_right: &(i16, i16), r: u8, g: u8, b: u8) {
-    for x in top_left.0..bottom_right.0 {
-        for y in top_left.1..bottom_right.1 {
-            screenwriter().draw_pixel(x as usize, y as usize, r, g, b);
-        }
+    let left = top_left.0.max(0);
+    let top = top_left.1.max(0);
+    if bottom_right.0 <= left || bottom_right.1 <= top {
+        return;
+    }
+    userlib::draw_rect(
+        left as usize,
+        top as usize,
+        (bottom_right.0 - left) as usize,
+        (bottom_right.1 - top) as usize,
+        u32::from_be_bytes([0, r, g, b]),
+    );
+}
+
+/// The last three digits of `number`, padded with zeros
+fn three_digits(number: u32) -> [u8; 3] {
+    let mut digits = [b'0'; 3];
+    let mut number = number;
+    for digit in digits.iter_mut().rev() {
+        *digit = (number % 10) as u8 + b'0';
+        number /= 10;
     }
+    digits
+}
+
+/// Writes `label` followed by the last three digits of `number` at `x`, `y`
+fn write_number(x: i16, y: i16, label: &str, number: u32) {
+    let mut text = TextBuffer::<16>::new();
+    let digits = three_digits(number);
+    let _ = write!(text, "{label}{}", core::str::from_utf8(&digits).unwrap());
+    userlib::write_text(x as usize, y as usize, text.as_str());
 }
 
 pub fn draw_arena(arena_size: &(i16, i16), lives: u8) {
@@ -101,17 +123,16 @@ pub fn draw_win_screen(arena_size: &(i16, i16)) {
         0,
         0,
     );
-    let writer = screenwriter();
-    writer.set_cursor(
+    userlib::write_text(
         arena_size.0 as usize / 2 - 35,
         arena_size.1 as usize / 2 - 30,
+        "YOU WIN!",
     );
-    writer.write_str("YOU WIN!");
-    writer.set_cursor(
+    userlib::write_text(
         arena_size.0 as usize / 2 - 110,
         arena_size.1 as usize / 2 + 10,
+        "Press Enter to play again",
     );
-    writer.write_str("Press Enter to play again");
 }
 
 pub fn draw_lose_screen(arena_size: &(i16, i16)) {
@@ -129,17 +150,16 @@ pub fn draw_lose_screen(arena_size: &(i16, i16)) {
         0,
         0,
     );
-    let writer = screenwriter();
-    writer.set_cursor(
+    userlib::write_text(
         arena_size.0 as usize / 2 - 35,
         arena_size.1 as usize / 2 - 30,
+        "YOU LOSE!",
     );
-    writer.write_str("YOU LOSE!");
-    writer.set_cursor(
+    userlib::write_text(
         arena_size.0 as usize / 2 - 110,
         arena_size.1 as usize / 2 + 10,
+        "Press Enter to play again",
     );
-    writer.write_str("Press Enter to play again");
 }
 
 pub fn draw_player(position: &(i16, i16)) {
@@ -631,18 +651,27 @@ pub fn clear_bullet(position: &(i16, i16)) {
 }
 
 pub fn draw_score(score: &u32, x: i16, y: i16) {
-    let writer = screenwriter();
-    writer.set_cursor(x as usize, y as usize);
-    writer.write_str("Score: ");
-    writer.write_number(score);
+    write_number(x, y, "Score: ", *score);
 }
 
 pub fn draw_win_lose(win: &u32, lose: &u32, x: i16, y: i16) {
-    let writer = screenwriter();
-    writer.set_cursor(x as usize, y as usize);
-    writer.write_str("Win: ");
-    writer.write_number(win);
-    writer.set_cursor(x as usize, (y + 20) as usize);
-    writer.write_str("Lose: ");
-    writer.write_number(lose);
+    write_number(x, y, "Win: ", *win);
+    write_number(x, y + 20, "Lose: ", *lose);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_are_padded_to_three_digits() {
+        assert_eq!(&three_digits(7), b"007");
+        assert_eq!(&three_digits(42), b"042");
+        assert_eq!(&three_digits(999), b"999");
+    }
+
+    #[test]
+    fn numbers_keep_their_last_three_digits() {
+        assert_eq!(&three_digits(1234), b"234");
+    }
 }
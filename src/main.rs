@@ -1,3 +1,10 @@
+use std::path::PathBuf;
+use std::process::{Child, ExitStatus};
+use std::time::{Duration, Instant};
+
+/// Longest a kernel test binary may run before QEMU is killed
+const TEST_TIMEOUT: Duration = Duration::from_secs(60);
+
 fn main() {
     // read env variables that were set in build script
     let uefi_path = env!("UEFI_PATH");
@@ -7,16 +14,106 @@ fn main() {
     let uefi = false;
 
     let mut cmd = std::process::Command::new("qemu-system-x86_64");
-    if uefi {
-        cmd.arg("-bios").arg(ovmf_prebuilt::ovmf_pure_efi());
+    // lets the kernel end the run with an exit code, see `kernel::exit_qemu`
+    cmd.arg("-device")
+        .arg("isa-debug-exit,iobase=0xf4,iosize=0x04");
+    let test = std::env::args_os().nth(1);
+    if let Some(kernel) = &test {
+        // a kernel test binary, passed by `cargo test` through the runner in .cargo/config.toml
+        let kernel = PathBuf::from(kernel);
+        let image = kernel.with_extension("img");
+        bootloader::BiosBoot::new(&kernel)
+            .create_disk_image(&image)
+            .unwrap();
         cmd.arg("-drive")
-            .arg(format!("format=raw,file={uefi_path}"));
+            .arg(format!("format=raw,file={}", image.display()));
         cmd.arg("-serial").arg("stdio");
+        cmd.arg("-display").arg("none");
+        // a triple fault would reset the machine and run the tests again, forever
+        cmd.arg("-no-reboot");
     } else {
-        cmd.arg("-drive")
-            .arg(format!("format=raw,file={bios_path}"));
-        cmd.arg("-serial").arg("stdio");
+        if uefi {
+            cmd.arg("-bios").arg(ovmf_prebuilt::ovmf_pure_efi());
+            cmd.arg("-drive")
+                .arg(format!("format=raw,file={uefi_path}"));
+            cmd.arg("-serial").arg("stdio");
+        } else {
+            cmd.arg("-drive")
+                .arg(format!("format=raw,file={bios_path}"));
+            cmd.arg("-serial").arg("stdio");
+        }
+        // COM2 carries the kernel's telemetry, one JSON record per game frame, to the QEMU
+        // character device in TELEMETRY, like `file:telemetry.ndjson` or
+        // `tcp::4444,server,nowait`. Without it, the kernel does not send any.
+        let mut flags = Vec::new();
+        match std::env::var("TELEMETRY") {
+            Ok(telemetry) => {
+                cmd.arg("-serial").arg(telemetry);
+                flags.push("telemetry");
+            }
+            Err(_) => {
+                cmd.arg("-serial").arg("null");
+            }
+        }
+        // makes panics end the run instead of leaving the crash screen up, see
+        // `kernel::end_crashed_run`
+        if std::env::var_os("CI").is_some() {
+            flags.push("exit-on-panic");
+        }
+        if !flags.is_empty() {
+            // read by `kernel::boot_flags`
+            cmd.arg("-fw_cfg")
+                .arg(format!("name=opt/lab-os/flags,string={}", flags.join(" ")));
+        }
     }
     let mut child = cmd.spawn().unwrap();
-    child.wait().unwrap();
+    if test.is_none() {
+        let status = child.wait().unwrap();
+        std::process::exit(exit_code(status));
+    }
+    match wait_timeout(&mut child, TEST_TIMEOUT) {
+        // the tests end the run through `kernel::exit_qemu`, so QEMU's own 0 means a reset
+        Some(status) if status.success() => {
+            eprintln!("QEMU exited without a test result, the kernel reset or shut down");
+            std::process::exit(1);
+        }
+        Some(status) => std::process::exit(exit_code(status)),
+        None => {
+            eprintln!("tests timed out after {} s", TEST_TIMEOUT.as_secs());
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Waits for QEMU to exit, and kills it after `timeout`. Returns None if it had to be killed.
+fn wait_timeout(child: &mut Child, timeout: Duration) -> Option<ExitStatus> {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            return Some(status);
+        }
+        if start.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return None;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Turns QEMU's exit status into this process's. A kernel that calls `kernel::exit_qemu(code)`
+/// makes QEMU exit with `(code << 1) | 1`, which gives back `code`, or 0 for
+/// `QemuExitCode::Success`. Any other status is QEMU's own, like 0 when its window is closed.
+fn exit_code(status: std::process::ExitStatus) -> i32 {
+    /// `QemuExitCode::Success`
+    const SUCCESS: i32 = 0x10;
+
+    match status.code() {
+        Some(status) if status == (SUCCESS << 1) | 1 => 0,
+        // QEMU itself fails with 1, which no kernel exit code maps to
+        Some(status) if status > 1 && status & 1 == 1 => status >> 1,
+        Some(status) => status,
+        // killed by a signal
+        None => 1,
+    }
 }
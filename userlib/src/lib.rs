@@ -0,0 +1,201 @@
+//! Syscall interface of the kernel, for programs running in ring 3.
+//!
+//! A syscall is `int 0x80` with its number in rax and its arguments in rdi, rsi, rdx, r10 and
+//! r8. The result comes back in rax.
+
+#![no_std]
+
+use core::arch::asm;
+use core::fmt;
+
+/// `exit(code) -> !`
+pub const EXIT: u64 = 0;
+/// `yield() -> 0`
+pub const YIELD: u64 = 1;
+/// `ticks() -> ticks`
+pub const TICKS: u64 = 2;
+/// `read_key() -> key or NO_KEY`
+pub const READ_KEY: u64 = 3;
+/// `draw_rect(x, y, width, height, 0xRRGGBB) -> 0`
+pub const DRAW_RECT: u64 = 4;
+/// `write_text(x, y, utf-8 pointer, length) -> 0, or ERROR if it starts off the screen`
+pub const WRITE_TEXT: u64 = 5;
+/// `sleep(milliseconds) -> 0`
+pub const SLEEP: u64 = 6;
+/// `now_ns() -> nanoseconds since the kernel started`
+pub const NOW_NS: u64 = 7;
+/// `write_console(utf-8 pointer, length) -> 0`, on the kernel's debug console
+pub const WRITE_CONSOLE: u64 = 8;
+/// `record_telemetry(utf-8 pointer, length) -> 0`, as one record on the telemetry channel
+pub const RECORD_TELEMETRY: u64 = 9;
+/// `read_message(buffer pointer, capacity) -> length or NO_MESSAGE`, ERROR if the message did
+/// not fit and was dropped
+pub const READ_MESSAGE: u64 = 10;
+
+/// Result of unknown syscalls and of syscalls with bad arguments
+pub const ERROR: u64 = u64::MAX;
+/// Result of `READ_KEY` when no key has been typed
+pub const NO_KEY: u64 = u64::MAX - 1;
+/// Result of `READ_MESSAGE` when no message is waiting
+pub const NO_MESSAGE: u64 = u64::MAX - 1;
+/// Set in `READ_KEY` results for keys without a character, with the `KeyCode` in the low bits.
+/// Other keys are returned as their character.
+pub const RAW_KEY: u64 = 1 << 32;
+
+/// A key read with `read_key`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    /// A key without a character, as a `pc_keyboard::KeyCode` cast to u32
+    Raw(u32),
+}
+
+#[inline(always)]
+unsafe fn syscall(number: u64, args: [u64; 5]) -> u64 {
+    let result;
+    asm!(
+        "int 0x80",
+        inlateout("rax") number => result,
+        in("rdi") args[0],
+        in("rsi") args[1],
+        in("rdx") args[2],
+        in("r10") args[3],
+        in("r8") args[4],
+    );
+    result
+}
+
+/// Ends the program with the given exit code.
+pub fn exit(code: u64) -> ! {
+    unsafe { syscall(EXIT, [code, 0, 0, 0, 0]) };
+    unreachable!("exit returned");
+}
+
+/// Gives the rest of the time slice to other threads.
+pub fn yield_now() {
+    unsafe { syscall(YIELD, [0; 5]) };
+}
+
+/// Number of timer interrupts since the kernel started.
+pub fn ticks() -> u64 {
+    unsafe { syscall(TICKS, [0; 5]) }
+}
+
+/// Returns the next key typed, if any.
+pub fn read_key() -> Option<Key> {
+    match unsafe { syscall(READ_KEY, [0; 5]) } {
+        NO_KEY | ERROR => None,
+        key if key & RAW_KEY != 0 => Some(Key::Raw(key as u32)),
+        key => char::from_u32(key as u32).map(Key::Char),
+    }
+}
+
+/// Fills a rectangle with the color `0xRRGGBB`.
+pub fn draw_rect(x: usize, y: usize, width: usize, height: usize, rgb: u32) {
+    let args = [x as u64, y as u64, width as u64, height as u64, rgb as u64];
+    unsafe { syscall(DRAW_RECT, args) };
+}
+
+/// Writes text with its top left corner at `x`, `y`. Text starting off the screen is not
+/// drawn, and text running past the bottom is cut.
+pub fn write_text(x: usize, y: usize, text: &str) {
+    let args = [
+        x as u64,
+        y as u64,
+        text.as_ptr() as u64,
+        text.len() as u64,
+        0,
+    ];
+    unsafe { syscall(WRITE_TEXT, args) };
+}
+
+/// Blocks the program for `ms` milliseconds, rounded up to whole timer ticks.
+pub fn sleep(ms: u64) {
+    unsafe { syscall(SLEEP, [ms, 0, 0, 0, 0]) };
+}
+
+/// Nanoseconds since the kernel started.
+pub fn now_ns() -> u64 {
+    unsafe { syscall(NOW_NS, [0; 5]) }
+}
+
+/// Writes text on the kernel's debug console, see `println!`.
+pub fn write_console(text: &str) {
+    let args = [text.as_ptr() as u64, text.len() as u64, 0, 0, 0];
+    unsafe { syscall(WRITE_CONSOLE, args) };
+}
+
+/// Sends `text` as one record on the kernel's telemetry channel. The kernel drops it while
+/// telemetry is off.
+pub fn record_telemetry(text: &str) {
+    let args = [text.as_ptr() as u64, text.len() as u64, 0, 0, 0];
+    unsafe { syscall(RECORD_TELEMETRY, args) };
+}
+
+/// Returns the next message the kernel sent to programs, if any, read into `buffer`. A message
+/// longer than `buffer` is dropped.
+pub fn read_message(buffer: &mut [u8]) -> Option<&str> {
+    let args = [buffer.as_mut_ptr() as u64, buffer.len() as u64, 0, 0, 0];
+    match unsafe { syscall(READ_MESSAGE, args) } {
+        NO_MESSAGE | ERROR => None,
+        len => core::str::from_utf8(&buffer[..len as usize]).ok(),
+    }
+}
+
+/// The kernel's debug console, for `write!`.
+pub struct Console;
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        write_console(text);
+        Ok(())
+    }
+}
+
+/// Prints to the kernel's debug console, with a newline.
+#[macro_export]
+macro_rules! println {
+    ($($arg:tt)*) => {{
+        use ::core::fmt::Write;
+        let _ = ::core::writeln!($crate::Console, $($arg)*);
+    }};
+}
+
+/// Text formatted with `write!` into a buffer of `N` bytes, for syscalls that take their text
+/// in one piece. Text that does not fit is cut off, and makes the write fail.
+pub struct TextBuffer<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> TextBuffer<N> {
+    pub const fn new() -> Self {
+        TextBuffer {
+            bytes: [0; N],
+            len: 0,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // only whole `str`s are ever copied in
+        unsafe { core::str::from_utf8_unchecked(&self.bytes[..self.len]) }
+    }
+}
+
+impl<const N: usize> Default for TextBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> fmt::Write for TextBuffer<N> {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        let end = self.len + text.len();
+        if end > N {
+            return Err(fmt::Error);
+        }
+        self.bytes[self.len..end].copy_from_slice(text.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}